@@ -0,0 +1,454 @@
+use crate::models::packet_models::AddressFamily;
+use crate::models::peers_dump::{AddressSource, Direction, Entry};
+use crate::models::{addr2bin, bin2addr, sample_addresses};
+use crate::tools::current_time;
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet};
+use std::hash::BuildHasher;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const NEW_BUCKETS: usize = 256;
+const TRIED_BUCKETS: usize = 64;
+const BUCKET_SIZE: usize = 64;
+
+/// Amount of new buckets the addresses from a single source group can land in
+const NEW_BUCKETS_PER_SOURCE_GROUP: u64 = 8;
+
+/// Amount of tried buckets the addresses of a single group can land in
+const TRIED_BUCKETS_PER_GROUP: u64 = 8;
+
+/// Failed attempts after which an address that never worked isn't handed out
+const MAX_FAILURES: u32 = 3;
+
+/// What the node knows about an address
+#[derive(Clone, Debug)]
+pub struct AddressInfo {
+    pub source: AddressSource,
+    pub first_seen: u64,
+    pub last_seen: u64,
+    pub last_success: Option<u64>,
+    pub failures: u32,
+    /// static key of the node behind the address, once connected
+    pub identity: Option<[u8; 32]>,
+    /// direction of the last session with the address
+    pub direction: Option<Direction>,
+    /// smoothed round trip time, in milliseconds
+    pub rtt: Option<u64>,
+    tried: bool,
+    bucket: usize,
+}
+
+impl AddressInfo {
+    /// Addresses which never worked and keep failing
+    fn is_terrible(&self) -> bool {
+        self.last_success.is_none() && self.failures >= MAX_FAILURES
+    }
+}
+
+/// Known addresses split into "new" ones, which were only heard of, and
+/// "tried" ones, which were connected to. Buckets are picked by the network
+/// group of the address and of whoever told us about it, so a single
+/// network can't fill the whole book
+#[derive(Clone)]
+pub struct AddressBook {
+    inner: Arc<Mutex<Book>>,
+}
+
+struct Book {
+    entries: HashMap<SocketAddr, AddressInfo>,
+    new: Vec<HashSet<SocketAddr>>,
+    tried: Vec<HashSet<SocketAddr>>,
+    /// secret bucketing key, an attacker can't aim at a bucket
+    key: RandomState,
+    /// addresses of this node, they are never stored
+    own: HashSet<SocketAddr>,
+}
+
+/// /16 for IPv4 and /32 for IPv6 addresses
+fn group(ip: &IpAddr) -> Vec<u8> {
+    let ip = ip.to_canonical();
+    match ip {
+        IpAddr::V4(ip) => vec![4, ip.octets()[0], ip.octets()[1]],
+        IpAddr::V6(ip) => {
+            let mut g = vec![6];
+            g.extend_from_slice(&ip.octets()[..4]);
+            g
+        }
+    }
+}
+
+impl Book {
+    fn new_bucket(&self, addr: &SocketAddr, from: &IpAddr) -> usize {
+        let source_group = group(from);
+        let h =
+            self.key.hash_one((group(&addr.ip()), &source_group)) % NEW_BUCKETS_PER_SOURCE_GROUP;
+        (self.key.hash_one((&source_group, h)) % NEW_BUCKETS as u64) as usize
+    }
+
+    fn tried_bucket(&self, addr: &SocketAddr) -> usize {
+        let h = self.key.hash_one(addr) % TRIED_BUCKETS_PER_GROUP;
+        (self.key.hash_one((group(&addr.ip()), h)) % TRIED_BUCKETS as u64) as usize
+    }
+
+    /// Removes the worst address of a full new bucket
+    fn evict_new(&mut self, bucket: usize) {
+        let worst = self.new[bucket]
+            .iter()
+            .filter_map(|addr| self.entries.get(addr).map(|info| (addr, info)))
+            .max_by_key(|(_, info)| (info.is_terrible(), info.failures, u64::MAX - info.last_seen))
+            .map(|(addr, _)| *addr);
+
+        if let Some(addr) = worst {
+            self.new[bucket].remove(&addr);
+            self.entries.remove(&addr);
+        }
+    }
+
+    /// Puts an address which isn't in any bucket into the new table
+    fn insert_new(&mut self, addr: SocketAddr, mut info: AddressInfo, from: &IpAddr) {
+        let bucket = self.new_bucket(&addr, from);
+        if self.new[bucket].len() >= BUCKET_SIZE {
+            self.evict_new(bucket);
+        }
+
+        info.tried = false;
+        info.bucket = bucket;
+        self.new[bucket].insert(addr);
+        self.entries.insert(addr, info);
+    }
+
+    /// Puts an address which isn't in any bucket into the tried table,
+    /// the oldest tried address of a full bucket goes back to the new table
+    fn insert_tried(&mut self, addr: SocketAddr, mut info: AddressInfo) {
+        let bucket = self.tried_bucket(&addr);
+        if self.tried[bucket].len() >= BUCKET_SIZE {
+            let oldest = self.tried[bucket]
+                .iter()
+                .filter_map(|addr| self.entries.get(addr).map(|info| (addr, info)))
+                .min_by_key(|(_, info)| info.last_success)
+                .map(|(addr, _)| *addr);
+
+            if let Some(old) = oldest {
+                self.tried[bucket].remove(&old);
+                if let Some(old_info) = self.entries.remove(&old) {
+                    self.insert_new(old, old_info, &old.ip());
+                }
+            }
+        }
+
+        info.tried = true;
+        info.bucket = bucket;
+        self.tried[bucket].insert(addr);
+        self.entries.insert(addr, info);
+    }
+
+    fn take(&mut self, addr: &SocketAddr) -> Option<AddressInfo> {
+        let info = self.entries.remove(addr)?;
+        if info.tried {
+            self.tried[info.bucket].remove(addr);
+        } else {
+            self.new[info.bucket].remove(addr);
+        }
+        Some(info)
+    }
+}
+
+impl Default for AddressBook {
+    fn default() -> AddressBook {
+        AddressBook {
+            inner: Arc::new(Mutex::new(Book {
+                entries: HashMap::new(),
+                new: vec![HashSet::new(); NEW_BUCKETS],
+                tried: vec![HashSet::new(); TRIED_BUCKETS],
+                key: RandomState::new(),
+                own: HashSet::new(),
+            })),
+        }
+    }
+}
+
+impl AddressBook {
+    pub fn new() -> AddressBook {
+        AddressBook::default()
+    }
+
+    /// Adds an address heard of from `from`, returns true if it wasn't known
+    pub fn add(&self, addr: SocketAddr, source: AddressSource, from: Option<IpAddr>) -> bool {
+        let mut book = self.inner.lock().unwrap();
+        let now = current_time();
+
+        if book.own.contains(&addr) {
+            return false;
+        }
+
+        if let Some(info) = book.entries.get_mut(&addr) {
+            info.last_seen = now;
+            return false;
+        }
+
+        let info = AddressInfo {
+            source,
+            first_seen: now,
+            last_seen: now,
+            last_success: None,
+            failures: 0,
+            identity: None,
+            direction: None,
+            rtt: None,
+            tried: false,
+            bucket: 0,
+        };
+        book.insert_new(addr, info, &from.unwrap_or(addr.ip()));
+
+        true
+    }
+
+    /// Records an established session, outbound ones prove that the address
+    /// is reachable and move it to the tried table
+    pub fn mark_success(&self, addr: &SocketAddr, identity: [u8; 32], direction: Direction) {
+        let mut book = self.inner.lock().unwrap();
+        let now = current_time();
+
+        if book.own.contains(addr) {
+            return;
+        }
+
+        if direction == Direction::Inbound {
+            if let Some(info) = book.entries.get_mut(addr) {
+                info.last_seen = now;
+                info.identity = Some(identity);
+                info.direction = Some(direction);
+            }
+            return;
+        }
+
+        let mut info = book.take(addr).unwrap_or(AddressInfo {
+            source: AddressSource::dialed,
+            first_seen: now,
+            last_seen: now,
+            last_success: None,
+            failures: 0,
+            identity: None,
+            direction: None,
+            rtt: None,
+            tried: false,
+            bucket: 0,
+        });
+        info.last_seen = now;
+        info.last_success = Some(now);
+        info.failures = 0;
+        info.identity = Some(identity);
+        info.direction = Some(direction);
+
+        book.insert_tried(*addr, info);
+    }
+
+    pub fn mark_failure(&self, addr: &SocketAddr) {
+        let mut book = self.inner.lock().unwrap();
+        if let Some(info) = book.entries.get_mut(addr) {
+            info.failures += 1;
+        }
+    }
+
+    /// Folds a new round trip time measurement into the smoothed one
+    pub fn record_rtt(&self, addr: &SocketAddr, rtt: Duration) {
+        let mut book = self.inner.lock().unwrap();
+        if let Some(info) = book.entries.get_mut(addr) {
+            let sample = rtt.as_millis() as u64;
+            info.rtt = Some(info.rtt.map_or(sample, |old| (old * 7 + sample) / 8));
+        }
+    }
+
+    /// Forgets an address of this node and refuses to store it again
+    pub fn mark_own(&self, addr: SocketAddr) {
+        let mut book = self.inner.lock().unwrap();
+        book.take(&addr);
+        book.own.insert(addr);
+    }
+
+    pub fn remove(&self, addr: &SocketAddr) {
+        self.inner.lock().unwrap().take(addr);
+    }
+
+    pub fn get(&self, addr: &SocketAddr) -> Option<AddressInfo> {
+        self.inner.lock().unwrap().entries.get(addr).cloned()
+    }
+
+    pub fn addresses(&self) -> Vec<SocketAddr> {
+        self.inner.lock().unwrap().entries.keys().copied().collect()
+    }
+
+    /// Picks at most `max` random addresses worth sharing with other nodes
+    pub fn sample(&self, max: usize, family: Option<AddressFamily>) -> Vec<SocketAddr> {
+        let book = self.inner.lock().unwrap();
+        let usable = book
+            .entries
+            .iter()
+            .filter(|(_, info)| !info.is_terrible())
+            .map(|(addr, _)| addr);
+        sample_addresses(usable, max, family)
+    }
+
+    pub fn dump(&self) -> Vec<Entry> {
+        let book = self.inner.lock().unwrap();
+        book.entries
+            .iter()
+            .map(|(addr, info)| Entry {
+                addr: addr2bin(addr),
+                source: info.source,
+                first_seen: info.first_seen,
+                last_seen: info.last_seen,
+                last_success: info.last_success,
+                failures: info.failures,
+                identity: info.identity.map(|i| i.to_vec()),
+                direction: info.direction,
+                tried: info.tried,
+                rtt: info.rtt,
+            })
+            .collect()
+    }
+
+    /// Restores dumped entries, the buckets are picked anew since the key
+    /// is different in every process
+    pub fn load(&self, entries: Vec<Entry>) {
+        let mut book = self.inner.lock().unwrap();
+
+        for entry in entries {
+            let Ok(addr) = bin2addr(&entry.addr) else {
+                continue;
+            };
+            if book.entries.contains_key(&addr) {
+                continue;
+            }
+
+            let info = AddressInfo {
+                source: entry.source,
+                first_seen: entry.first_seen,
+                last_seen: entry.last_seen,
+                last_success: entry.last_success,
+                failures: entry.failures,
+                identity: entry.identity.and_then(|i| i.try_into().ok()),
+                direction: entry.direction,
+                rtt: entry.rtt,
+                tried: false,
+                bucket: 0,
+            };
+
+            if entry.tried {
+                book.insert_tried(addr, info);
+            } else {
+                book.insert_new(addr, info, &addr.ip());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod address_book_tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn addr(a: u8, b: u8, c: u8, port: u16) -> SocketAddr {
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, a, b, c)), port)
+    }
+
+    #[test]
+    fn add_test() {
+        let book = AddressBook::new();
+
+        assert!(book.add(addr(0, 0, 1, 5050), AddressSource::announce, None));
+        assert!(!book.add(addr(0, 0, 1, 5050), AddressSource::get_nodes, None));
+
+        let info = book.get(&addr(0, 0, 1, 5050)).unwrap();
+        assert_eq!(info.source, AddressSource::announce);
+        assert!(!info.tried);
+    }
+
+    #[test]
+    fn mark_success_test() {
+        let book = AddressBook::new();
+        let a = addr(0, 0, 1, 5050);
+        book.add(a, AddressSource::announce, None);
+        book.mark_failure(&a);
+
+        // inbound sessions don't prove the address is reachable
+        book.mark_success(&a, [1; 32], Direction::Inbound);
+        let info = book.get(&a).unwrap();
+        assert!(!info.tried);
+        assert_eq!(info.identity, Some([1; 32]));
+
+        book.mark_success(&a, [1; 32], Direction::Outbound);
+        let info = book.get(&a).unwrap();
+        assert!(info.tried);
+        assert_eq!(info.failures, 0);
+        assert!(info.last_success.is_some());
+        assert_eq!(info.direction, Some(Direction::Outbound));
+
+        book.record_rtt(&a, Duration::from_millis(80));
+        book.record_rtt(&a, Duration::from_millis(160));
+        assert_eq!(book.get(&a).unwrap().rtt, Some(90));
+    }
+
+    #[test]
+    fn own_address_test() {
+        let book = AddressBook::new();
+        let a = addr(0, 0, 1, 5050);
+        book.add(a, AddressSource::announce, None);
+
+        book.mark_own(a);
+        assert!(book.get(&a).is_none());
+        assert!(!book.add(a, AddressSource::get_nodes, None));
+        book.mark_success(&a, [1; 32], Direction::Outbound);
+        assert!(book.get(&a).is_none());
+    }
+
+    #[test]
+    fn single_source_bounded_test() {
+        let book = AddressBook::new();
+        let attacker = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+
+        for i in 0..5000u16 {
+            let a = addr((i >> 8) as u8, i as u8, 1, 5050);
+            book.add(a, AddressSource::announce, Some(attacker));
+        }
+
+        let max = (NEW_BUCKETS_PER_SOURCE_GROUP as usize) * BUCKET_SIZE;
+        assert!(book.addresses().len() <= max);
+    }
+
+    #[test]
+    fn sample_skips_terrible_test() {
+        let book = AddressBook::new();
+        let good = addr(0, 0, 1, 5050);
+        let bad = addr(1, 0, 1, 5050);
+        book.add(good, AddressSource::announce, None);
+        book.add(bad, AddressSource::announce, None);
+        for _ in 0..MAX_FAILURES {
+            book.mark_failure(&bad);
+        }
+
+        assert_eq!(book.sample(10, None), vec![good]);
+        assert!(book.sample(10, Some(AddressFamily::ipv6)).is_empty());
+    }
+
+    #[test]
+    fn dump_load_test() {
+        let book = AddressBook::new();
+        let a = addr(0, 0, 1, 5050);
+        let b = addr(1, 0, 1, 5050);
+        book.add(a, AddressSource::announce, None);
+        book.add(b, AddressSource::get_nodes, None);
+        book.mark_success(&b, [2; 32], Direction::Outbound);
+
+        let restored = AddressBook::new();
+        restored.load(book.dump());
+
+        assert!(!restored.get(&a).unwrap().tried);
+        let info = restored.get(&b).unwrap();
+        assert!(info.tried);
+        assert_eq!(info.identity, Some([2; 32]));
+        assert_eq!(info.source, AddressSource::get_nodes);
+    }
+}
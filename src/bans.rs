@@ -0,0 +1,233 @@
+use crate::models::bans_dump::Ban;
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+/// Points of misbehavior forgiven every minute
+const DECAY_PER_MINUTE: u32 = 1;
+
+/// Things a peer can do wrong, weighted by how likely they are malicious
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Offence {
+    /// frame which fails to authenticate, decompress or parse
+    MalformedPacket,
+    OversizedPacket,
+    UnsolicitedResponse,
+    /// announce of an address which is invalid or can't be a peer
+    BadAddress,
+    /// relayed transaction with a bad signature, relays have to verify
+    InvalidTransaction,
+    /// block or header which fails validation
+    InvalidBlock,
+}
+
+impl Offence {
+    fn weight(self) -> u32 {
+        match self {
+            Offence::MalformedPacket => 50,
+            Offence::OversizedPacket => 100,
+            Offence::UnsolicitedResponse => 10,
+            Offence::BadAddress => 20,
+            Offence::InvalidTransaction => 20,
+            Offence::InvalidBlock => 50,
+        }
+    }
+}
+
+/// Ban settings of the node
+#[derive(Clone, Debug)]
+pub struct Policy {
+    /// score at which a peer gets banned
+    pub threshold: u32,
+    /// length of a temporary ban, in seconds
+    pub duration: u64,
+    /// temporary bans after which the next one is permanent
+    pub permanent_after: u32,
+    /// peers which are never banned
+    pub whitelist: HashSet<IpAddr>,
+}
+
+struct Score {
+    points: u32,
+    updated: u64,
+}
+
+struct Record {
+    /// None for permanent bans
+    until: Option<u64>,
+    /// how many times the peer was banned
+    count: u32,
+}
+
+struct Bans {
+    policy: Policy,
+    scores: HashMap<IpAddr, Score>,
+    bans: HashMap<IpAddr, Record>,
+}
+
+/// Misbehavior scores of peers and the bans they earned, peers are
+/// identified by IP since that's all the accept loop knows about them
+#[derive(Clone)]
+pub struct BanList {
+    inner: Arc<Mutex<Bans>>,
+}
+
+impl Record {
+    fn active(&self, now: u64) -> bool {
+        self.until.is_none_or(|until| now < until)
+    }
+}
+
+impl BanList {
+    pub fn new(policy: Policy) -> BanList {
+        BanList {
+            inner: Arc::new(Mutex::new(Bans {
+                policy,
+                scores: HashMap::new(),
+                bans: HashMap::new(),
+            })),
+        }
+    }
+
+    pub fn is_banned(&self, ip: &IpAddr, now: u64) -> bool {
+        let bans = self.inner.lock().unwrap();
+        bans.bans
+            .get(&ip.to_canonical())
+            .is_some_and(|r| r.active(now))
+    }
+
+    /// Adds the offence to the score of the peer, returns true if that got
+    /// the peer banned
+    pub fn misbehaved(&self, ip: &IpAddr, offence: Offence, now: u64) -> bool {
+        let ip = ip.to_canonical();
+        let mut bans = self.inner.lock().unwrap();
+        if bans.policy.whitelist.contains(&ip) {
+            return false;
+        }
+
+        let score = bans.scores.entry(ip).or_insert(Score {
+            points: 0,
+            updated: now,
+        });
+        let minutes = now.saturating_sub(score.updated) / 60;
+        let forgiven = u32::try_from(minutes).unwrap_or(u32::MAX);
+        score.points = score
+            .points
+            .saturating_sub(forgiven.saturating_mul(DECAY_PER_MINUTE))
+            .saturating_add(offence.weight());
+        score.updated = now;
+
+        if score.points < bans.policy.threshold {
+            return false;
+        }
+        bans.scores.remove(&ip);
+
+        let count = bans.bans.get(&ip).map_or(0, |r| r.count) + 1;
+        let until = if count > bans.policy.permanent_after {
+            None
+        } else {
+            Some(now + bans.policy.duration)
+        };
+        bans.bans.insert(ip, Record { until, count });
+
+        true
+    }
+
+    /// Expired bans are dumped as well, their count is kept so that
+    /// repeated offenders end up banned for good
+    pub fn dump(&self) -> Vec<Ban> {
+        let bans = self.inner.lock().unwrap();
+        bans.bans
+            .iter()
+            .map(|(ip, r)| Ban {
+                ip: match ip {
+                    IpAddr::V4(ip) => ip.octets().to_vec(),
+                    IpAddr::V6(ip) => ip.octets().to_vec(),
+                },
+                until: r.until,
+                count: r.count,
+            })
+            .collect()
+    }
+
+    pub fn load(&self, entries: Vec<Ban>) {
+        let mut bans = self.inner.lock().unwrap();
+        for entry in entries {
+            let ip = match entry.ip.len() {
+                4 => IpAddr::from(<[u8; 4]>::try_from(entry.ip).unwrap()),
+                16 => IpAddr::from(<[u8; 16]>::try_from(entry.ip).unwrap()),
+                _ => continue,
+            };
+            bans.bans.insert(
+                ip.to_canonical(),
+                Record {
+                    until: entry.until,
+                    count: entry.count,
+                },
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod bans_tests {
+    use super::*;
+
+    fn policy() -> Policy {
+        Policy {
+            threshold: 100,
+            duration: 3600,
+            permanent_after: 1,
+            whitelist: HashSet::from(["10.0.0.9".parse().unwrap()]),
+        }
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn score_test() {
+        let bans = BanList::new(policy());
+        let peer = ip("10.0.0.1");
+
+        for _ in 0..9 {
+            assert!(!bans.misbehaved(&peer, Offence::UnsolicitedResponse, 0));
+        }
+        assert!(!bans.is_banned(&peer, 0));
+
+        // the score decays, an offence an hour later doesn't add up
+        assert!(!bans.misbehaved(&peer, Offence::UnsolicitedResponse, 3600));
+        assert!(bans.misbehaved(&peer, Offence::OversizedPacket, 3600));
+        assert!(bans.is_banned(&peer, 3600));
+
+        // whitelisted peers are never banned
+        assert!(!bans.misbehaved(&ip("10.0.0.9"), Offence::OversizedPacket, 0));
+    }
+
+    #[test]
+    fn escalation_test() {
+        let bans = BanList::new(policy());
+        let peer = ip("10.0.0.1");
+
+        assert!(bans.misbehaved(&peer, Offence::OversizedPacket, 0));
+        assert!(!bans.is_banned(&peer, 3600));
+
+        // the second ban is permanent
+        assert!(bans.misbehaved(&peer, Offence::OversizedPacket, 3600));
+        assert!(bans.is_banned(&peer, u64::MAX));
+    }
+
+    #[test]
+    fn dump_test() {
+        let bans = BanList::new(policy());
+        bans.misbehaved(&ip("10.0.0.1"), Offence::OversizedPacket, 0);
+        bans.misbehaved(&ip("::ffff:10.0.0.2"), Offence::OversizedPacket, 0);
+
+        let restored = BanList::new(policy());
+        restored.load(bans.dump());
+        assert!(restored.is_banned(&ip("10.0.0.1"), 0));
+        assert!(restored.is_banned(&ip("10.0.0.2"), 0));
+        assert!(!restored.is_banned(&ip("10.0.0.1"), 3600));
+    }
+}
@@ -0,0 +1,718 @@
+use crate::config::{MAX_BLOCK_SIZE, MAX_BLOCK_TRANSACTIONS};
+use crate::consensus::{Branch, Consensus};
+use crate::errors::chain_errors::ChainError;
+use crate::ledger::Ledger;
+use crate::mempool::Mempool;
+use crate::models::block_models::{Block, Header};
+use crate::models::transaction_models::Transaction;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use tokio::sync::{broadcast, Mutex, MutexGuard};
+
+/// Headers served in a single get_headers response at most
+pub const MAX_HEADERS: usize = 500;
+/// Blocks served in a single get_blocks response at most
+pub const MAX_BLOCKS: usize = 20;
+/// Seconds a block may be ahead of the local clock
+const MAX_FUTURE: u64 = 2 * 60;
+const REORGS_CAPACITY: usize = 16;
+/// Bytes of a produced block kept for its header and the encoding of the
+/// transactions list
+const HEADER_ALLOWANCE: usize = 1024;
+
+/// Switch of the main chain to another branch
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Reorg {
+    /// last block both branches share
+    pub ancestor: [u8; 32],
+    /// ids of the blocks which left the main chain, oldest first
+    pub reverted: Vec<[u8; 32]>,
+    /// ids of the blocks which replaced them
+    pub applied: Vec<[u8; 32]>,
+}
+
+/// Transaction of the main chain along with the proof of its inclusion
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Inclusion {
+    pub transaction: Transaction,
+    /// header of the block including the transaction
+    pub header: Header,
+    /// position of the transaction in the block
+    pub index: u64,
+    /// Merkle proof against the transactions root of the block
+    pub proof: Vec<[u8; 32]>,
+}
+
+/// Genesis block of the network, its parent field commits to the initial
+/// allocations, so that nodes with different allocations don't agree on it
+pub fn genesis(allocations: &[([u8; 32], u64)], timestamp: u64) -> Block {
+    let allocations = rmp_serde::to_vec(allocations).unwrap();
+
+    Block {
+        header: Header {
+            parent: Sha256::digest(allocations).to_vec(),
+            height: 0,
+            timestamp,
+            transactions_root: Block::transactions_root(&[]).to_vec(),
+            producer: Vec::new(),
+            signature: Vec::new(),
+        },
+        transactions: Vec::new(),
+    }
+}
+
+/// Main chain of blocks and the state they lead to
+#[derive(Clone)]
+pub struct Chain {
+    ledger: Ledger,
+    mempool: Mempool,
+    consensus: Arc<dyn Consensus>,
+    reorgs: broadcast::Sender<Reorg>,
+    /// blocks are synced from one peer at a time and not produced
+    /// meanwhile, both would only race for the same tip
+    syncing: Arc<Mutex<()>>,
+}
+
+impl Chain {
+    /// Starts the chain with the genesis block, or checks that the stored
+    /// chain starts with it
+    pub fn open(
+        ledger: Ledger,
+        mempool: Mempool,
+        consensus: Arc<dyn Consensus>,
+        allocations: &[([u8; 32], u64)],
+        timestamp: u64,
+    ) -> Result<Chain, ChainError> {
+        let genesis = genesis(allocations, timestamp);
+        ledger
+            .init_genesis(&genesis, allocations)
+            .map_err(ChainError::Ledger)?;
+
+        if ledger.block_id_at(0).map_err(ChainError::Ledger)? != Some(genesis.id()) {
+            return Err(ChainError::GenesisMismatch);
+        }
+
+        Ok(Chain {
+            ledger,
+            mempool,
+            consensus,
+            reorgs: broadcast::channel(REORGS_CAPACITY).0,
+            syncing: Arc::new(Mutex::new(())),
+        })
+    }
+
+    pub fn tip(&self) -> Result<Header, ChainError> {
+        self.ledger
+            .tip()
+            .map_err(ChainError::Ledger)?
+            .ok_or(ChainError::GenesisMismatch)
+    }
+
+    /// Header of a block of any known branch
+    pub fn header(&self, id: &[u8; 32]) -> Result<Option<Header>, ChainError> {
+        self.ledger.header(id).map_err(ChainError::Ledger)
+    }
+
+    pub fn is_main(&self, header: &Header) -> Result<bool, ChainError> {
+        let id = self
+            .ledger
+            .block_id_at(header.height)
+            .map_err(ChainError::Ledger)?;
+        Ok(id == Some(header.id()))
+    }
+
+    /// Weight of the branch ending with the block
+    fn weight(&self, header: &Header) -> Result<u128, ChainError> {
+        self.ledger
+            .weight(&header.id())
+            .map_err(ChainError::Ledger)?
+            .ok_or(ChainError::BadParent)
+    }
+
+    /// Weights of the branches ending with every one of the consecutive
+    /// headers following the ancestor
+    fn weights(&self, ancestor: &Header, headers: &[Header]) -> Result<Vec<u128>, ChainError> {
+        let mut weight = self.weight(ancestor)?;
+        Ok(headers
+            .iter()
+            .map(|h| {
+                weight += self.consensus.weight(h) as u128;
+                weight
+            })
+            .collect())
+    }
+
+    /// Notifies about every switch of the main chain to another branch
+    pub fn subscribe(&self) -> broadcast::Receiver<Reorg> {
+        self.reorgs.subscribe()
+    }
+
+    /// Ids of main chain blocks for get_headers: the latest ten, then
+    /// exponentially sparser down to the genesis block
+    pub fn locator(&self) -> Result<Vec<[u8; 32]>, ChainError> {
+        let mut height = self.tip()?.height;
+        let mut step = 1;
+        let mut locator = Vec::new();
+
+        loop {
+            if let Some(id) = self
+                .ledger
+                .block_id_at(height)
+                .map_err(ChainError::Ledger)?
+            {
+                locator.push(id);
+            }
+            if height == 0 {
+                return Ok(locator);
+            }
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            height = height.saturating_sub(step);
+        }
+    }
+
+    /// Main chain headers after the newest locator block on the main chain,
+    /// after the genesis block if there is none
+    pub fn headers_after(
+        &self,
+        locator: &[Vec<u8>],
+        max: usize,
+    ) -> Result<Vec<Header>, ChainError> {
+        let mut start = 1;
+        for id in locator {
+            let Ok(id) = <[u8; 32]>::try_from(id.as_slice()) else {
+                continue;
+            };
+            let Some(header) = self.ledger.header(&id).map_err(ChainError::Ledger)? else {
+                continue;
+            };
+            if self
+                .ledger
+                .block_id_at(header.height)
+                .map_err(ChainError::Ledger)?
+                == Some(id)
+            {
+                start = header.height + 1;
+                break;
+            }
+        }
+
+        let mut headers = Vec::new();
+        for height in start..start + max.min(MAX_HEADERS) as u64 {
+            let Some(id) = self
+                .ledger
+                .block_id_at(height)
+                .map_err(ChainError::Ledger)?
+            else {
+                break;
+            };
+            match self.ledger.header(&id).map_err(ChainError::Ledger)? {
+                Some(header) => headers.push(header),
+                None => break,
+            }
+        }
+
+        Ok(headers)
+    }
+
+    /// Known blocks out of the requested ones, in order, until their
+    /// encoding would take more than `max_size` bytes. The first block is
+    /// always included, so that every block can be synced.
+    pub fn blocks(&self, ids: &[Vec<u8>], max_size: usize) -> Result<Vec<Block>, ChainError> {
+        let mut blocks = Vec::new();
+        let mut size: usize = 0;
+        for id in ids.iter().take(MAX_BLOCKS) {
+            let Ok(id) = <[u8; 32]>::try_from(id.as_slice()) else {
+                continue;
+            };
+            if let Some(block) = self.ledger.block(&id).map_err(ChainError::Ledger)? {
+                size =
+                    size.saturating_add(rmp_serde::to_vec(&block).map_or(usize::MAX, |b| b.len()));
+                if size > max_size && !blocks.is_empty() {
+                    break;
+                }
+                blocks.push(block);
+            }
+        }
+
+        Ok(blocks)
+    }
+
+    /// Applied transaction with the proof that its block includes it
+    pub fn transaction(&self, id: &[u8; 32]) -> Result<Option<Inclusion>, ChainError> {
+        let Some((block_id, index)) = self.ledger.location(id).map_err(ChainError::Ledger)? else {
+            return Ok(None);
+        };
+        let Some(block) = self.ledger.block(&block_id).map_err(ChainError::Ledger)? else {
+            return Ok(None);
+        };
+        let Some(proof) = block.proof(index as usize) else {
+            return Ok(None);
+        };
+
+        Ok(Some(Inclusion {
+            transaction: block.transactions[index as usize].clone(),
+            header: block.header.clone(),
+            index,
+            proof,
+        }))
+    }
+
+    /// Checks that the header can follow the parent under the rules of
+    /// the consensus
+    pub fn check_header(
+        &self,
+        header: &Header,
+        parent: &Header,
+        now: u64,
+    ) -> Result<(), ChainError> {
+        if header.parent != parent.id() {
+            return Err(ChainError::BadParent);
+        }
+        if header.height != parent.height + 1 {
+            return Err(ChainError::BadHeight {
+                height: header.height,
+                parent_height: parent.height,
+            });
+        }
+        if header.timestamp < parent.timestamp || header.timestamp > now + MAX_FUTURE {
+            return Err(ChainError::BadTimestamp);
+        }
+
+        self.consensus
+            .validate_header(header, parent)
+            .map_err(ChainError::Consensus)
+    }
+
+    /// Whether the branch of headers following the ancestor should replace
+    /// the main chain, by the cumulative weights of both
+    pub fn prefers(&self, ancestor: &Header, headers: &[Header]) -> Result<bool, ChainError> {
+        let Some(last) = headers.last() else {
+            return Ok(false);
+        };
+        let tip = self.tip()?;
+
+        let current = Branch {
+            weight: self.weight(&tip)?,
+            tip,
+        };
+        let candidate = Branch {
+            tip: last.clone(),
+            weight: *self.weights(ancestor, headers)?.last().unwrap(),
+        };
+        Ok(self.consensus.select_fork(&current, &candidate))
+    }
+
+    /// Keeps the headers of a branch the node doesn't switch to, so that
+    /// its weight is known once it grows
+    pub fn track(&self, ancestor: &Header, headers: &[Header]) -> Result<(), ChainError> {
+        let weights = self.weights(ancestor, headers)?;
+        let tracked: Vec<(Header, u128)> = headers.iter().cloned().zip(weights).collect();
+        self.ledger.track(&tracked).map_err(ChainError::Ledger)
+    }
+
+    fn check_block(&self, block: &Block, parent: &Header, now: u64) -> Result<(), ChainError> {
+        if block.transactions.len() > *MAX_BLOCK_TRANSACTIONS {
+            return Err(ChainError::TooManyTransactions {
+                count: block.transactions.len(),
+                max: *MAX_BLOCK_TRANSACTIONS,
+            });
+        }
+        let size = rmp_serde::to_vec(block).map_or(usize::MAX, |b| b.len());
+        if size > *MAX_BLOCK_SIZE {
+            return Err(ChainError::TooLarge {
+                size,
+                max: *MAX_BLOCK_SIZE,
+            });
+        }
+        if block.header.transactions_root != Block::transactions_root(&block.transactions) {
+            return Err(ChainError::BadTransactionsRoot);
+        }
+        self.check_header(&block.header, parent, now)
+    }
+
+    /// Validates the block against the tip and applies it, its transactions
+    /// leave the mempool
+    pub fn apply_block(&self, block: &Block, now: u64) -> Result<(), ChainError> {
+        let tip = self.tip()?;
+        self.check_block(block, &tip, now)?;
+        let weight = self.weight(&tip)? + self.consensus.weight(&block.header) as u128;
+
+        self.ledger
+            .apply_block(block, weight)
+            .map_err(ChainError::Ledger)?;
+        for transaction in &block.transactions {
+            self.mempool.remove_applied(transaction);
+        }
+
+        Ok(())
+    }
+
+    /// Switches the main chain to the blocks following the ancestor.
+    /// Transactions of the reverted blocks which the new branch doesn't
+    /// contain go back to the mempool.
+    pub fn reorganize(
+        &self,
+        ancestor: &Header,
+        blocks: &[Block],
+        now: u64,
+    ) -> Result<(), ChainError> {
+        let mut parent = ancestor;
+        for block in blocks {
+            self.check_block(block, parent, now)?;
+            parent = &block.header;
+        }
+        let headers: Vec<Header> = blocks.iter().map(|b| b.header.clone()).collect();
+        let weighted: Vec<(Block, u128)> = blocks
+            .iter()
+            .cloned()
+            .zip(self.weights(ancestor, &headers)?)
+            .collect();
+
+        let reverted = self
+            .ledger
+            .reorganize(ancestor, &weighted)
+            .map_err(ChainError::Ledger)?;
+
+        for transaction in blocks.iter().flat_map(|b| &b.transactions) {
+            self.mempool.remove_applied(transaction);
+        }
+        let returned = reverted
+            .iter()
+            .flat_map(|b| b.transactions.iter().cloned())
+            .filter(|tx| matches!(self.ledger.transaction(&tx.id()), Ok(None)))
+            .collect();
+        self.mempool.restore(
+            returned,
+            |sender| {
+                self.ledger
+                    .account(sender)
+                    .ok()
+                    .flatten()
+                    .unwrap_or_default()
+            },
+            now,
+        );
+
+        // nobody may be listening
+        let _ = self.reorgs.send(Reorg {
+            ancestor: ancestor.id(),
+            reverted: reverted.iter().map(|b| b.id()).collect(),
+            applied: blocks.iter().map(|b| b.id()).collect(),
+        });
+
+        Ok(())
+    }
+
+    /// Produces a block on the tip with the best paying transactions of the
+    /// mempool, if the consensus lets this node produce one now
+    pub fn produce(&self, now: u64) -> Result<Option<Block>, ChainError> {
+        let tip = self.tip()?;
+
+        // the selection keeps the order of nonces, so any prefix of it
+        // is valid as well
+        let mut size = HEADER_ALLOWANCE;
+        let transactions = self
+            .mempool
+            .select(*MAX_BLOCK_TRANSACTIONS)
+            .into_iter()
+            .take_while(|tx| {
+                size = size.saturating_add(rmp_serde::to_vec(tx).map_or(usize::MAX, |t| t.len()));
+                size <= *MAX_BLOCK_SIZE
+            })
+            .collect();
+        let Some(block) = self.consensus.produce_block(&tip, transactions, now) else {
+            return Ok(None);
+        };
+
+        match self.apply_block(&block, now) {
+            Ok(()) => Ok(Some(block)),
+            Err(e) if e.is_local() => Err(e),
+            // the mempool went out of date, the turn isn't lost for that
+            Err(e) => {
+                println!("Produced an invalid block: {}", e);
+                let Some(block) = self.consensus.produce_block(&tip, Vec::new(), now) else {
+                    return Ok(None);
+                };
+                self.apply_block(&block, now)?;
+                Ok(Some(block))
+            }
+        }
+    }
+
+    /// Held while applying synced blocks or producing a block, never
+    /// across a network request
+    pub async fn lock_sync(&self) -> MutexGuard<'_, ()> {
+        self.syncing.lock().await
+    }
+}
+
+#[cfg(test)]
+mod chain_tests {
+    use super::*;
+    use crate::consensus::ProofOfAuthority;
+    use crate::mempool::Limits;
+    use crate::models::block_models::verify_merkle_proof;
+    use crate::models::transaction_models::Transaction;
+    use ed25519_dalek::SigningKey;
+    use rand_core::OsRng;
+
+    fn validator() -> SigningKey {
+        SigningKey::from_bytes(&[7; 32])
+    }
+
+    fn consensus() -> Arc<dyn Consensus> {
+        Arc::new(ProofOfAuthority::new(
+            vec![validator().verifying_key().to_bytes()],
+            1,
+            Some(validator()),
+        ))
+    }
+
+    fn mempool() -> Mempool {
+        Mempool::new(Limits {
+            max_size: 10,
+            max_per_sender: 10,
+            min_fee: 0,
+            expiry: 60,
+        })
+    }
+
+    fn chain(allocations: &[([u8; 32], u64)]) -> Chain {
+        Chain::open(Ledger::in_memory(), mempool(), consensus(), allocations, 0).unwrap()
+    }
+
+    fn next(parent: &Header, transactions: Vec<Transaction>) -> Block {
+        let mut header = Header {
+            parent: parent.id().to_vec(),
+            height: parent.height + 1,
+            timestamp: parent.timestamp + 1,
+            transactions_root: Block::transactions_root(&transactions).to_vec(),
+            producer: Vec::new(),
+            signature: Vec::new(),
+        };
+        header.sign(&validator());
+
+        Block {
+            header,
+            transactions,
+        }
+    }
+
+    #[test]
+    fn apply_block_test() {
+        let key = SigningKey::generate(&mut OsRng);
+        let chain = chain(&[(key.verifying_key().to_bytes(), 100)]);
+        let genesis = chain.tip().unwrap();
+
+        let mut block = next(
+            &genesis,
+            vec![Transaction::new_signed(&key, [2; 32], 10, 1, 0)],
+        );
+        block.transactions.clear();
+        assert!(matches!(
+            chain.apply_block(&block, 10),
+            Err(ChainError::BadTransactionsRoot)
+        ));
+
+        let mut oversized = Transaction::new_signed(&key, [2; 32], 10, 1, 0);
+        oversized.signature = vec![0; *MAX_BLOCK_SIZE];
+        assert!(matches!(
+            chain.apply_block(&next(&genesis, vec![oversized]), 10),
+            Err(ChainError::TooLarge { .. })
+        ));
+
+        let mut block = next(&genesis, Vec::new());
+        block.header.timestamp = 10 + MAX_FUTURE + 1;
+        assert!(matches!(
+            chain.apply_block(&block, 10),
+            Err(ChainError::BadTimestamp)
+        ));
+
+        let block = next(
+            &genesis,
+            vec![Transaction::new_signed(&key, [2; 32], 10, 1, 0)],
+        );
+        chain.apply_block(&block, 10).unwrap();
+        assert_eq!(chain.tip().unwrap(), block.header);
+        assert!(matches!(
+            chain.apply_block(&next(&genesis, Vec::new()), 10),
+            Err(ChainError::BadParent)
+        ));
+
+        let mut unsigned = next(&block.header, Vec::new());
+        unsigned.header.signature.clear();
+        assert!(matches!(
+            chain.apply_block(&unsigned, 10),
+            Err(ChainError::Consensus(_))
+        ));
+    }
+
+    #[test]
+    fn transaction_test() {
+        let key = SigningKey::generate(&mut OsRng);
+        let chain = chain(&[(key.verifying_key().to_bytes(), 1000)]);
+        let mut parent = chain.tip().unwrap();
+        let mut nonce = 0;
+
+        for count in [1, 2, 3, 4, 5, 7] {
+            let transactions = (0..count)
+                .map(|_| {
+                    nonce += 1;
+                    Transaction::new_signed(&key, [2; 32], 10, 1, nonce - 1)
+                })
+                .collect();
+            let block = next(&parent, transactions);
+            chain.apply_block(&block, 100).unwrap();
+
+            for (index, transaction) in block.transactions.iter().enumerate() {
+                let inclusion = chain.transaction(&transaction.id()).unwrap().unwrap();
+                assert_eq!(inclusion.header, block.header);
+                assert_eq!(inclusion.index, index as u64);
+                assert_eq!(inclusion.transaction, *transaction);
+
+                let proof: Vec<Vec<u8>> = inclusion.proof.iter().map(|h| h.to_vec()).collect();
+                let root = &block.header.transactions_root;
+                assert!(verify_merkle_proof(
+                    &transaction.id(),
+                    inclusion.index,
+                    &proof,
+                    root
+                ));
+                assert!(!verify_merkle_proof(
+                    &transaction.id(),
+                    inclusion.index + 1,
+                    &proof,
+                    root
+                ));
+            }
+            parent = block.header;
+        }
+
+        assert!(chain.transaction(&[0; 32]).unwrap().is_none());
+    }
+
+    #[test]
+    fn produce_test() {
+        let key = SigningKey::generate(&mut OsRng);
+        let sender = key.verifying_key().to_bytes();
+        let chain = chain(&[(sender, 100)]);
+        let tx = Transaction::new_signed(&key, [2; 32], 10, 1, 0);
+        chain
+            .mempool
+            .insert(
+                tx.clone(),
+                chain.ledger.account(&sender).unwrap().unwrap(),
+                0,
+            )
+            .unwrap();
+
+        // too early for the next block
+        assert!(chain.produce(0).unwrap().is_none());
+
+        let block = chain.produce(1).unwrap().unwrap();
+        assert_eq!(block.transactions, vec![tx]);
+        assert_eq!(chain.tip().unwrap(), block.header);
+        assert_eq!(chain.mempool.len(), 0);
+    }
+
+    #[test]
+    fn reorganize_test() {
+        let key = SigningKey::generate(&mut OsRng);
+        let sender = key.verifying_key().to_bytes();
+        let chain = chain(&[(sender, 100)]);
+        let genesis = chain.tip().unwrap();
+        let mut reorgs = chain.subscribe();
+
+        let reverted = Transaction::new_signed(&key, [2; 32], 10, 1, 0);
+        let ours = next(&genesis, vec![reverted.clone()]);
+        chain.apply_block(&ours, 10).unwrap();
+
+        // a branch as heavy as the main chain doesn't replace it
+        let first = next(&genesis, Vec::new());
+        assert!(!chain
+            .prefers(&genesis, std::slice::from_ref(&first.header))
+            .unwrap());
+        chain
+            .track(&genesis, std::slice::from_ref(&first.header))
+            .unwrap();
+        assert!(!chain.is_main(&first.header).unwrap());
+
+        let second = next(&first.header, Vec::new());
+        assert!(chain
+            .prefers(&first.header, std::slice::from_ref(&second.header))
+            .unwrap());
+        chain
+            .reorganize(&genesis, &[first.clone(), second.clone()], 10)
+            .unwrap();
+
+        assert_eq!(chain.tip().unwrap(), second.header);
+        assert!(chain.is_main(&first.header).unwrap());
+        assert!(!chain.is_main(&ours.header).unwrap());
+        assert_eq!(
+            reorgs.try_recv().unwrap(),
+            Reorg {
+                ancestor: genesis.id(),
+                reverted: vec![ours.id()],
+                applied: vec![first.id(), second.id()],
+            }
+        );
+
+        // the transaction of the reverted block is pending again
+        assert_eq!(chain.ledger.account(&sender).unwrap().unwrap().balance, 100);
+        assert!(chain.mempool.get(&reverted.id()).is_some());
+    }
+
+    #[test]
+    fn headers_after_test() {
+        let chain = chain(&[]);
+        let genesis = chain.tip().unwrap();
+        let mut headers = vec![genesis.clone()];
+        for _ in 0..30 {
+            let block = next(headers.last().unwrap(), Vec::new());
+            chain.apply_block(&block, 100).unwrap();
+            headers.push(block.header);
+        }
+
+        let locator = chain.locator().unwrap();
+        assert_eq!(locator[0], headers[30].id());
+        assert_eq!(*locator.last().unwrap(), genesis.id());
+
+        // the first known block of the locator is where the headers start
+        let locator = vec![
+            vec![7; 32],
+            headers[20].id().to_vec(),
+            genesis.id().to_vec(),
+        ];
+        assert_eq!(chain.headers_after(&locator, 5).unwrap(), headers[21..26]);
+        assert_eq!(chain.headers_after(&[], 2).unwrap(), headers[1..3]);
+
+        let ids = vec![headers[3].id().to_vec(), vec![7; 32]];
+        assert_eq!(chain.blocks(&ids, usize::MAX).unwrap().len(), 1);
+
+        // blocks past the size limit are left out
+        let ids: Vec<_> = headers[1..4].iter().map(|h| h.id().to_vec()).collect();
+        let sizes: Vec<_> = chain
+            .blocks(&ids, usize::MAX)
+            .unwrap()
+            .iter()
+            .map(|b| rmp_serde::to_vec(b).unwrap().len())
+            .collect();
+        assert_eq!(chain.blocks(&ids, sizes[0] + sizes[1]).unwrap().len(), 2);
+        assert_eq!(chain.blocks(&ids, sizes[0] - 1).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn genesis_test() {
+        let ledger = Ledger::in_memory();
+        Chain::open(ledger.clone(), mempool(), consensus(), &[([1; 32], 5)], 0).unwrap();
+        assert_eq!(ledger.account(&[1; 32]).unwrap().unwrap().balance, 5);
+
+        // other allocations make another network
+        assert!(matches!(
+            Chain::open(ledger, mempool(), consensus(), &[([1; 32], 6)], 0),
+            Err(ChainError::GenesisMismatch)
+        ));
+    }
+}
@@ -1,7 +1,192 @@
+use crate::tools::from_hex;
 use lazy_static::lazy_static;
+use std::collections::HashSet;
 use std::env::var;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
 
 lazy_static! {
     pub static ref SERVER_ADDRESS: SocketAddr = var("SERVER_ADDRESS").unwrap().parse().unwrap();
+    pub static ref NETWORK_ID: String = var("NETWORK_ID").unwrap_or("mainnet".to_string());
+    /// Largest encrypted frame accepted from a peer, in bytes
+    pub static ref MAX_FRAME_SIZE: usize = var("MAX_FRAME_SIZE")
+        .map(|v| v.parse().unwrap())
+        .unwrap_or(1024 * 1024);
+    /// Largest packet a frame may decompress into, in bytes
+    pub static ref MAX_PAYLOAD_SIZE: usize = var("MAX_PAYLOAD_SIZE")
+        .map(|v| v.parse().unwrap())
+        .unwrap_or(4 * 1024 * 1024);
+    /// Amount of outbound connections the node keeps
+    pub static ref TARGET_OUTBOUND: usize = var("TARGET_OUTBOUND")
+        .map(|v| v.parse().unwrap())
+        .unwrap_or(8);
+    /// Failed dials after which an address may be forgotten
+    pub static ref EVICTION_FAILURES: u32 = var("EVICTION_FAILURES")
+        .map(|v| v.parse().unwrap())
+        .unwrap_or(10);
+    /// How long an address has to keep failing before it is forgotten
+    pub static ref EVICTION_WINDOW: Duration = Duration::from_secs(
+        var("EVICTION_WINDOW")
+            .map(|v| v.parse().unwrap())
+            .unwrap_or(24 * 60 * 60)
+    );
+    pub static ref MAX_INBOUND: usize = var("MAX_INBOUND")
+        .map(|v| v.parse().unwrap())
+        .unwrap_or(32);
+    pub static ref MAX_OUTBOUND: usize = var("MAX_OUTBOUND")
+        .map(|v| v.parse().unwrap())
+        .unwrap_or(16);
+    pub static ref MAX_PER_IP: usize = var("MAX_PER_IP")
+        .map(|v| v.parse().unwrap())
+        .unwrap_or(2);
+    /// Connections allowed from a single /24 (IPv4) or /64 (IPv6)
+    pub static ref MAX_PER_SUBNET: usize = var("MAX_PER_SUBNET")
+        .map(|v| v.parse().unwrap())
+        .unwrap_or(4);
+    /// Inbound slots kept for whitelisted peers
+    pub static ref RESERVED_SLOTS: usize = var("RESERVED_SLOTS")
+        .map(|v| v.parse().unwrap())
+        .unwrap_or(4);
+    /// Comma separated IPs of trusted peers
+    pub static ref WHITELIST: HashSet<IpAddr> = var("WHITELIST")
+        .map(|v| {
+            v.split(',')
+                .filter(|ip| !ip.trim().is_empty())
+                .map(|ip| ip.trim().parse().unwrap())
+                .collect()
+        })
+        .unwrap_or_default();
+    /// Misbehavior score at which a peer is banned
+    pub static ref BAN_THRESHOLD: u32 = var("BAN_THRESHOLD")
+        .map(|v| v.parse().unwrap())
+        .unwrap_or(100);
+    /// Length of a temporary ban, in seconds
+    pub static ref BAN_DURATION: u64 = var("BAN_DURATION")
+        .map(|v| v.parse().unwrap())
+        .unwrap_or(24 * 60 * 60);
+    /// Temporary bans of a peer after which it is banned for good
+    pub static ref PERMANENT_BAN_AFTER: u32 = var("PERMANENT_BAN_AFTER")
+        .map(|v| v.parse().unwrap())
+        .unwrap_or(3);
+    /// Requests of any kind a peer may send at once
+    pub static ref REQUESTS_BURST: u32 = var("REQUESTS_BURST")
+        .map(|v| v.parse().unwrap())
+        .unwrap_or(50);
+    pub static ref REQUESTS_PER_MINUTE: u32 = var("REQUESTS_PER_MINUTE")
+        .map(|v| v.parse().unwrap())
+        .unwrap_or(600);
+    pub static ref ANNOUNCE_BURST: u32 = var("ANNOUNCE_BURST")
+        .map(|v| v.parse().unwrap())
+        .unwrap_or(10);
+    pub static ref ANNOUNCE_PER_MINUTE: u32 = var("ANNOUNCE_PER_MINUTE")
+        .map(|v| v.parse().unwrap())
+        .unwrap_or(30);
+    pub static ref GET_NODES_BURST: u32 = var("GET_NODES_BURST")
+        .map(|v| v.parse().unwrap())
+        .unwrap_or(1);
+    pub static ref GET_NODES_PER_MINUTE: u32 = var("GET_NODES_PER_MINUTE")
+        .map(|v| v.parse().unwrap())
+        .unwrap_or(1);
+    /// Silence after which a session is pinged
+    pub static ref PING_INTERVAL: Duration = Duration::from_secs(
+        var("PING_INTERVAL")
+            .map(|v| v.parse().unwrap())
+            .unwrap_or(30)
+    );
+    /// Silence after which a session is considered dead
+    pub static ref IDLE_TIMEOUT: Duration = Duration::from_secs(
+        var("IDLE_TIMEOUT")
+            .map(|v| v.parse().unwrap())
+            .unwrap_or(90)
+    );
+    /// Accept private, link-local and other non-routable peer addresses,
+    /// for networks which don't span the internet
+    pub static ref ALLOW_NON_ROUTABLE: bool = var("ALLOW_NON_ROUTABLE")
+        .map(|v| v.parse().unwrap())
+        .unwrap_or(false);
+    /// Connect to announced addresses before storing and gossiping them
+    pub static ref PROBE_ANNOUNCES: bool = var("PROBE_ANNOUNCES")
+        .map(|v| v.parse().unwrap())
+        .unwrap_or(false);
+    /// Peers which have to agree on the external address before it is used
+    pub static ref EXTERNAL_ADDRESS_REPORTS: usize = var("EXTERNAL_ADDRESS_REPORTS")
+        .map(|v| v.parse().unwrap())
+        .unwrap_or(3);
+    /// File the ledger is stored in
+    pub static ref LEDGER_PATH: String = var("LEDGER_PATH").unwrap_or("ledger.redb".to_string());
+    /// Transactions kept in the mempool at most
+    pub static ref MEMPOOL_SIZE: usize = var("MEMPOOL_SIZE")
+        .map(|v| v.parse().unwrap())
+        .unwrap_or(5000);
+    /// Pending transactions of a single sender kept in the mempool at most
+    pub static ref MEMPOOL_PER_SENDER: usize = var("MEMPOOL_PER_SENDER")
+        .map(|v| v.parse().unwrap())
+        .unwrap_or(25);
+    /// Lowest fee of a transaction accepted into the mempool
+    pub static ref MIN_FEE: u64 = var("MIN_FEE")
+        .map(|v| v.parse().unwrap())
+        .unwrap_or(1);
+    /// Seconds a transaction stays in the mempool without being included
+    pub static ref MEMPOOL_EXPIRY: u64 = var("MEMPOOL_EXPIRY")
+        .map(|v| v.parse().unwrap())
+        .unwrap_or(3 * 60 * 60);
+    /// Initial balances of the network, as `public key hex:amount` pairs
+    /// separated by commas
+    pub static ref GENESIS_ALLOCATIONS: Vec<([u8; 32], u64)> = var("GENESIS_ALLOCATIONS")
+        .map(|v| {
+            v.split(',')
+                .filter(|a| !a.trim().is_empty())
+                .map(|a| {
+                    let (key, amount) = a.trim().split_once(':').unwrap();
+                    let key = from_hex(key).unwrap().try_into().unwrap();
+                    (key, amount.parse().unwrap())
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    /// Unix time of the genesis block
+    pub static ref GENESIS_TIMESTAMP: u64 = var("GENESIS_TIMESTAMP")
+        .map(|v| v.parse().unwrap())
+        .unwrap_or(0);
+    /// Public keys of the nodes which may produce blocks, hex separated by commas
+    pub static ref VALIDATORS: Vec<[u8; 32]> = var("VALIDATORS")
+        .map(|v| {
+            v.split(',')
+                .filter(|k| !k.trim().is_empty())
+                .map(|k| from_hex(k.trim()).unwrap().try_into().unwrap())
+                .collect()
+        })
+        .unwrap_or_default();
+    /// File with the Ed25519 seed blocks are signed with, if the node is a validator
+    pub static ref VALIDATOR_KEY_FILE: String =
+        var("VALIDATOR_KEY_FILE").unwrap_or("validator.key".to_string());
+    /// Seconds between blocks
+    pub static ref BLOCK_INTERVAL: u64 = var("BLOCK_INTERVAL")
+        .map(|v| v.parse().unwrap())
+        .unwrap_or(5);
+    /// Transactions a block may contain at most
+    pub static ref MAX_BLOCK_TRANSACTIONS: usize = var("MAX_BLOCK_TRANSACTIONS")
+        .map(|v| v.parse().unwrap())
+        .unwrap_or(500);
+    /// Largest encoded block in bytes, a block has to fit a get_blocks
+    /// response within a single frame
+    pub static ref MAX_BLOCK_SIZE: usize = var("MAX_BLOCK_SIZE")
+        .map(|v| v.parse().unwrap())
+        .unwrap_or(768 * 1024);
+    /// Header and block requests a peer may send at once
+    pub static ref SYNC_BURST: u32 = var("SYNC_BURST")
+        .map(|v| v.parse().unwrap())
+        .unwrap_or(20);
+    /// Header and block requests a peer may send per minute in the long run
+    pub static ref SYNC_PER_MINUTE: u32 = var("SYNC_PER_MINUTE")
+        .map(|v| v.parse().unwrap())
+        .unwrap_or(120);
+    /// Transactions a peer may submit or announce at once
+    pub static ref TRANSACTIONS_BURST: u32 = var("TRANSACTIONS_BURST")
+        .map(|v| v.parse().unwrap())
+        .unwrap_or(100);
+    /// Transactions a peer may submit or announce per minute in the long run
+    pub static ref TRANSACTIONS_PER_MINUTE: u32 = var("TRANSACTIONS_PER_MINUTE")
+        .map(|v| v.parse().unwrap())
+        .unwrap_or(600);
 }
@@ -0,0 +1,235 @@
+use rand::seq::SliceRandom;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use tokio::time::{Duration, Instant};
+
+const BASE_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_BACKOFF: Duration = Duration::from_secs(30 * 60);
+
+/// What happened to an outbound connection
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DialEvent {
+    Established,
+    /// the session could not be set up
+    Failed,
+    /// an established session ended
+    Disconnected,
+    /// the connection limits didn't allow dialing the address
+    Refused,
+}
+
+struct Backoff {
+    failures: u32,
+    first_failure: Instant,
+    retry_at: Instant,
+}
+
+/// Decides which addresses to dial to keep the target amount of outbound
+/// connections, failed addresses are retried with exponential backoff
+pub struct ConnectionManager {
+    target: usize,
+    eviction_failures: u32,
+    eviction_window: Duration,
+    /// addresses being dialed or connected to
+    active: HashSet<SocketAddr>,
+    established: usize,
+    backoff: HashMap<SocketAddr, Backoff>,
+}
+
+impl ConnectionManager {
+    pub fn new(
+        target: usize,
+        eviction_failures: u32,
+        eviction_window: Duration,
+    ) -> ConnectionManager {
+        ConnectionManager {
+            target,
+            eviction_failures,
+            eviction_window,
+            active: HashSet::with_capacity(target),
+            established: 0,
+            backoff: HashMap::new(),
+        }
+    }
+
+    /// Picks addresses to dial among the known ones, they count as active
+    /// until a `Failed` or `Disconnected` event is handled
+    pub fn select(&mut self, mut candidates: Vec<SocketAddr>, now: Instant) -> Vec<SocketAddr> {
+        let wanted = self.target.saturating_sub(self.active.len());
+        if wanted == 0 {
+            return Vec::new();
+        }
+
+        candidates.shuffle(&mut rand::rng());
+        let selected: Vec<SocketAddr> = candidates
+            .into_iter()
+            .filter(|addr| !self.active.contains(addr))
+            .filter(|addr| self.backoff.get(addr).is_none_or(|b| b.retry_at <= now))
+            .take(wanted)
+            .collect();
+
+        self.active.extend(selected.iter().copied());
+        selected
+    }
+
+    /// Handles an event of a dialed address, returns true if the address
+    /// kept failing for long enough to be forgotten
+    pub fn handle(&mut self, addr: SocketAddr, event: DialEvent, now: Instant) -> bool {
+        match event {
+            DialEvent::Established => {
+                // nothing worked before, the failures were likely ours
+                if self.established == 0 {
+                    self.backoff.clear();
+                }
+                self.established += 1;
+                self.backoff.remove(&addr);
+                false
+            }
+            DialEvent::Disconnected => {
+                self.active.remove(&addr);
+                self.established = self.established.saturating_sub(1);
+                false
+            }
+            DialEvent::Failed => {
+                self.active.remove(&addr);
+                self.fail(addr, now)
+            }
+            DialEvent::Refused => {
+                // retried later, but it's not the address' fault
+                self.active.remove(&addr);
+                self.delay(addr, now);
+                false
+            }
+        }
+    }
+
+    fn fail(&mut self, addr: SocketAddr, now: Instant) -> bool {
+        let backoff = self.backoff.entry(addr).or_insert(Backoff {
+            failures: 0,
+            first_failure: now,
+            retry_at: now,
+        });
+        backoff.failures += 1;
+        backoff.retry_at = now + Self::jittered(backoff.failures);
+
+        // while nothing is connected the network is likely down,
+        // addresses must not be forgotten because of that
+        let evict = self.established > 0
+            && backoff.failures >= self.eviction_failures
+            && now.duration_since(backoff.first_failure) >= self.eviction_window;
+        if evict {
+            self.backoff.remove(&addr);
+        }
+
+        evict
+    }
+
+    fn delay(&mut self, addr: SocketAddr, now: Instant) {
+        let retry_at = now + Self::jittered(1);
+        match self.backoff.get_mut(&addr) {
+            Some(backoff) => backoff.retry_at = backoff.retry_at.max(retry_at),
+            None => {
+                self.backoff.insert(
+                    addr,
+                    Backoff {
+                        failures: 0,
+                        first_failure: now,
+                        retry_at,
+                    },
+                );
+            }
+        }
+    }
+
+    /// Exponential delay for the given amount of failures, randomized so
+    /// that peers don't retry in lockstep
+    fn jittered(failures: u32) -> Duration {
+        BASE_BACKOFF
+            .saturating_mul(1 << failures.saturating_sub(1).min(16))
+            .min(MAX_BACKOFF)
+            .mul_f64(rand::random_range(0.5..1.5))
+    }
+}
+
+#[cfg(test)]
+mod connections_tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::new([10, 0, 0, 1].into(), port)
+    }
+
+    #[test]
+    fn select_target_test() {
+        let mut manager = ConnectionManager::new(2, 3, Duration::from_secs(60));
+        let now = Instant::now();
+        let candidates = vec![addr(1), addr(2), addr(3)];
+
+        let selected = manager.select(candidates.clone(), now);
+        assert_eq!(selected.len(), 2);
+
+        // the slots are taken while the addresses are being dialed
+        assert!(manager.select(candidates.clone(), now).is_empty());
+
+        manager.handle(selected[0], DialEvent::Failed, now);
+        let again = manager.select(candidates, now);
+        assert_eq!(again.len(), 1);
+        assert!(!selected.contains(&again[0]));
+    }
+
+    #[test]
+    fn backoff_test() {
+        let mut manager = ConnectionManager::new(1, 3, Duration::from_secs(60));
+        let now = Instant::now();
+
+        assert_eq!(manager.select(vec![addr(1)], now), vec![addr(1)]);
+        manager.handle(addr(1), DialEvent::Failed, now);
+
+        assert!(manager.select(vec![addr(1)], now).is_empty());
+        let later = now + BASE_BACKOFF * 3;
+        assert_eq!(manager.select(vec![addr(1)], later), vec![addr(1)]);
+    }
+
+    #[test]
+    fn offline_eviction_test() {
+        let mut manager = ConnectionManager::new(2, 3, Duration::from_secs(60));
+        let now = Instant::now();
+        manager.handle(addr(2), DialEvent::Established, now);
+        manager.handle(addr(2), DialEvent::Disconnected, now);
+
+        // nothing is connected, the failures don't evict
+        for i in 0..5 {
+            let at = now + Duration::from_secs(i * 60);
+            assert!(!manager.handle(addr(1), DialEvent::Failed, at));
+        }
+    }
+
+    #[test]
+    fn eviction_window_test() {
+        let mut manager = ConnectionManager::new(2, 3, Duration::from_secs(60));
+        let now = Instant::now();
+        manager.handle(addr(2), DialEvent::Established, now);
+
+        // enough failures, but all of them within the window
+        for _ in 0..5 {
+            assert!(!manager.handle(addr(1), DialEvent::Failed, now));
+        }
+        assert!(manager.handle(addr(1), DialEvent::Failed, now + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn outage_resume_test() {
+        let mut manager = ConnectionManager::new(2, 3, Duration::from_secs(60));
+        let now = Instant::now();
+
+        manager.select(vec![addr(1), addr(2)], now);
+        manager.handle(addr(1), DialEvent::Failed, now);
+        manager.handle(addr(2), DialEvent::Failed, now);
+        assert!(manager.select(vec![addr(1), addr(2)], now).is_empty());
+
+        // the first success after an outage resets the backoff of everyone
+        manager.select(vec![addr(3)], now);
+        manager.handle(addr(3), DialEvent::Established, now);
+        assert_eq!(manager.select(vec![addr(1)], now), vec![addr(1)]);
+    }
+}
@@ -0,0 +1,228 @@
+use crate::errors::consensus_errors::ConsensusError;
+use crate::models::block_models::{Block, Header};
+use crate::models::transaction_models::Transaction;
+use ed25519_dalek::SigningKey;
+
+/// Tip of a branch of the chain with the weight of its blocks
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Branch {
+    pub tip: Header,
+    pub weight: u128,
+}
+
+/// Rules deciding who may produce blocks and which branch is the chain
+pub trait Consensus: Send + Sync {
+    /// Checks the consensus fields of a header following the parent
+    fn validate_header(&self, header: &Header, parent: &Header) -> Result<(), ConsensusError>;
+
+    /// Weight the block adds to its branch
+    fn weight(&self, header: &Header) -> u64;
+
+    /// Whether the node should switch from the current branch to the
+    /// candidate, by default if the candidate is heavier, so that the
+    /// first seen branch wins a tie
+    fn select_fork(&self, current: &Branch, candidate: &Branch) -> bool {
+        candidate.weight > current.weight
+    }
+
+    /// Block on top of the parent, if this node may produce one at `now`
+    fn produce_block(
+        &self,
+        parent: &Header,
+        transactions: Vec<Transaction>,
+        now: u64,
+    ) -> Option<Block>;
+}
+
+/// Fixed set of validators taking turns: the validator in turn may produce
+/// a block `interval` seconds after its parent, every next one in the
+/// order an `interval` later as a backup. Blocks of validators closer to
+/// their turn weigh more.
+pub struct ProofOfAuthority {
+    validators: Vec<[u8; 32]>,
+    /// seconds between blocks
+    interval: u64,
+    /// key of this node, if it is a validator
+    key: Option<SigningKey>,
+}
+
+impl ProofOfAuthority {
+    pub fn new(
+        validators: Vec<[u8; 32]>,
+        interval: u64,
+        key: Option<SigningKey>,
+    ) -> ProofOfAuthority {
+        ProofOfAuthority {
+            validators,
+            interval,
+            key,
+        }
+    }
+
+    /// How many turns the producer is away from the one in turn
+    fn offset(&self, producer: &[u8], height: u64) -> Option<u64> {
+        let index = self.validators.iter().position(|v| v == producer)? as u64;
+        let count = self.validators.len() as u64;
+        let in_turn = height % count;
+
+        Some((index + count - in_turn) % count)
+    }
+
+    /// Earliest time the producer may build on the parent
+    fn earliest(&self, parent: &Header, offset: u64) -> u64 {
+        parent.timestamp + self.interval * (offset + 1)
+    }
+}
+
+impl Consensus for ProofOfAuthority {
+    fn validate_header(&self, header: &Header, parent: &Header) -> Result<(), ConsensusError> {
+        let offset = self
+            .offset(&header.producer, header.height)
+            .ok_or(ConsensusError::UnknownProducer)?;
+        if !header.verify_signature() {
+            return Err(ConsensusError::BadSignature);
+        }
+        if header.timestamp < self.earliest(parent, offset) {
+            return Err(ConsensusError::TooEarly);
+        }
+
+        Ok(())
+    }
+
+    fn weight(&self, header: &Header) -> u64 {
+        self.offset(&header.producer, header.height)
+            .map_or(0, |offset| self.validators.len() as u64 - offset)
+    }
+
+    fn produce_block(
+        &self,
+        parent: &Header,
+        transactions: Vec<Transaction>,
+        now: u64,
+    ) -> Option<Block> {
+        let key = self.key.as_ref()?;
+        let height = parent.height + 1;
+        let offset = self.offset(&key.verifying_key().to_bytes(), height)?;
+        if now < self.earliest(parent, offset) {
+            return None;
+        }
+
+        let mut header = Header {
+            parent: parent.id().to_vec(),
+            height,
+            timestamp: now,
+            transactions_root: Block::transactions_root(&transactions).to_vec(),
+            producer: Vec::new(),
+            signature: Vec::new(),
+        };
+        header.sign(key);
+
+        Some(Block {
+            header,
+            transactions,
+        })
+    }
+}
+
+#[cfg(test)]
+mod consensus_tests {
+    use super::*;
+
+    fn keys() -> Vec<SigningKey> {
+        (1..=3).map(|i| SigningKey::from_bytes(&[i; 32])).collect()
+    }
+
+    fn engine(key: Option<SigningKey>) -> ProofOfAuthority {
+        let validators = keys()
+            .iter()
+            .map(|k| k.verifying_key().to_bytes())
+            .collect();
+        ProofOfAuthority::new(validators, 5, key)
+    }
+
+    fn genesis() -> Header {
+        Header {
+            parent: vec![0; 32],
+            height: 0,
+            timestamp: 100,
+            transactions_root: Block::transactions_root(&[]).to_vec(),
+            producer: Vec::new(),
+            signature: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn turns_test() {
+        let keys = keys();
+        let genesis = genesis();
+
+        // block 1 is the turn of the second validator
+        let in_turn = engine(Some(keys[1].clone()));
+        assert!(in_turn.produce_block(&genesis, Vec::new(), 104).is_none());
+        let block = in_turn.produce_block(&genesis, Vec::new(), 105).unwrap();
+        assert!(engine(None)
+            .validate_header(&block.header, &genesis)
+            .is_ok());
+        assert_eq!(engine(None).weight(&block.header), 3);
+
+        // the next validator is a backup after another interval
+        let backup = engine(Some(keys[2].clone()));
+        assert!(backup.produce_block(&genesis, Vec::new(), 105).is_none());
+        let block = backup.produce_block(&genesis, Vec::new(), 110).unwrap();
+        assert_eq!(engine(None).weight(&block.header), 2);
+
+        // an early block of a backup is refused
+        let mut early = block.header.clone();
+        early.timestamp = 105;
+        early.sign(&keys[2]);
+        assert!(matches!(
+            engine(None).validate_header(&early, &genesis),
+            Err(ConsensusError::TooEarly)
+        ));
+    }
+
+    #[test]
+    fn validate_test() {
+        let genesis = genesis();
+        let outsider = SigningKey::from_bytes(&[9; 32]);
+        let mut header = engine(Some(keys()[1].clone()))
+            .produce_block(&genesis, Vec::new(), 200)
+            .unwrap()
+            .header;
+
+        header.timestamp += 1;
+        assert!(matches!(
+            engine(None).validate_header(&header, &genesis),
+            Err(ConsensusError::BadSignature)
+        ));
+
+        header.sign(&outsider);
+        assert!(matches!(
+            engine(None).validate_header(&header, &genesis),
+            Err(ConsensusError::UnknownProducer)
+        ));
+        assert!(ProofOfAuthority::new(Vec::new(), 5, Some(outsider))
+            .produce_block(&genesis, Vec::new(), 200)
+            .is_none());
+    }
+
+    #[test]
+    fn select_fork_test() {
+        let engine = engine(None);
+        let current = Branch {
+            tip: genesis(),
+            weight: 5,
+        };
+        let candidate = Branch {
+            tip: genesis(),
+            weight: 5,
+        };
+
+        assert!(!engine.select_fork(&current, &candidate));
+        let heavier = Branch {
+            weight: 6,
+            ..candidate
+        };
+        assert!(engine.select_fork(&current, &heavier));
+    }
+}
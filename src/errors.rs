@@ -16,10 +16,23 @@ pub mod models_errors {
     #[derive(Debug, Clone, Error)]
     #[error("Bad address")]
     pub struct BadAddress;
+
+    #[derive(Debug, Clone, Error)]
+    pub enum InvalidTransaction {
+        #[error("Malformed transaction")]
+        Malformed,
+        #[error("Transaction is not canonically encoded")]
+        NonCanonical,
+        #[error("Bad public key in the transaction")]
+        BadKey,
+        #[error("Bad transaction signature")]
+        BadSignature,
+    }
 }
 
 pub mod node_errors {
     use super::*;
+    use crate::models::packet_models;
     use getrandom::Error as grandErr;
 
     #[derive(Debug, Clone, Error)]
@@ -45,7 +58,173 @@ pub mod node_errors {
         }
     }
 
+    #[allow(dead_code)]
     #[derive(Debug, Clone, Error)]
     #[error("Peer closed connection")]
     pub struct ConnectionClosed {}
+
+    #[derive(Debug, Clone, Error)]
+    #[error("Unexpected frame counter: expected {}, got {}", self.expected, self.got)]
+    pub struct UnexpectedCounter {
+        pub expected: u64,
+        pub got: u64,
+    }
+
+    #[derive(Debug, Clone, Error)]
+    #[error("Packet is too large: {} bytes, at most {} allowed", self.size, self.max)]
+    pub struct PacketTooLarge {
+        pub size: usize,
+        pub max: usize,
+    }
+
+    #[derive(Debug, Clone, Error)]
+    #[error("Malformed packet: {}", self.reason)]
+    pub struct MalformedPacket {
+        pub reason: String,
+    }
+
+    #[derive(Debug, Clone, Error)]
+    #[error("Frame authentication failed")]
+    pub struct AuthenticationFailed {}
+
+    #[derive(Debug, Clone, Error)]
+    #[error("Handshake failed")]
+    pub struct HandshakeFailed {}
+
+    #[derive(Debug, Clone, Error)]
+    pub enum RequestError {
+        #[error("Peer is not connected")]
+        NotConnected,
+        #[error("Session with the peer was closed")]
+        Closed,
+        #[error("Request timed out")]
+        Timeout,
+        #[error("Peer rejected the request: {0:?}")]
+        Rejected(packet_models::ErrorCode),
+    }
+
+    #[derive(Debug, Clone, Error)]
+    #[error("Connected to itself")]
+    pub struct SelfConnection {}
+
+    #[derive(Debug, Clone, Error)]
+    #[error("Already connected to {}", self.addr)]
+    pub struct DuplicateSession {
+        pub addr: std::net::SocketAddr,
+    }
+
+    #[derive(Debug, Clone, Error)]
+    #[error("Incompatible peer: {:?}", self.reason)]
+    pub struct IncompatiblePeer {
+        pub reason: packet_models::ErrorCode,
+    }
+}
+
+pub mod ledger_errors {
+    use super::*;
+
+    #[derive(Debug, Clone, Error)]
+    pub enum LedgerError {
+        #[error("Ledger storage error: {0}")]
+        Storage(String),
+        #[error("Invalid transaction: {0}")]
+        Invalid(models_errors::InvalidTransaction),
+        #[error("Transaction was already applied")]
+        AlreadyApplied,
+        #[error("Unexpected nonce: expected {expected}, got {got}")]
+        BadNonce { expected: u64, got: u64 },
+        #[error("Insufficient funds")]
+        InsufficientFunds,
+        #[error("Balance overflow")]
+        Overflow,
+        #[error("Block doesn't extend the tip of the chain")]
+        NotExtendingTip,
+    }
+}
+
+pub mod mempool_errors {
+    use super::*;
+
+    #[derive(Debug, Clone, Error)]
+    pub enum MempoolError {
+        #[error("Invalid transaction: {0}")]
+        Invalid(models_errors::InvalidTransaction),
+        #[error("Transaction is already in the mempool")]
+        AlreadyKnown,
+        #[error("Fee is too low, at least {min} required")]
+        FeeTooLow { min: u64 },
+        #[error("Unexpected nonce: expected {expected}, got {got}")]
+        BadNonce { expected: u64, got: u64 },
+        #[error("Insufficient funds")]
+        InsufficientFunds,
+        #[error("Too many pending transactions of the sender")]
+        TooManyFromSender,
+        #[error("Mempool is full")]
+        Full,
+        #[error("Ledger error: {0}")]
+        Ledger(ledger_errors::LedgerError),
+    }
+}
+
+pub mod chain_errors {
+    use super::*;
+
+    #[derive(Debug, Clone, Error)]
+    pub enum ChainError {
+        #[error("{0}")]
+        Ledger(ledger_errors::LedgerError),
+        #[error("Block {height} doesn't follow block {parent_height}")]
+        BadHeight { height: u64, parent_height: u64 },
+        #[error("Block doesn't link to its parent")]
+        BadParent,
+        #[error("Block timestamp is out of range")]
+        BadTimestamp,
+        #[error("Transactions don't match the header")]
+        BadTransactionsRoot,
+        #[error("Genesis block doesn't match the stored chain")]
+        GenesisMismatch,
+        #[error("Block has {count} transactions, at most {max} allowed")]
+        TooManyTransactions { count: usize, max: usize },
+        #[error("Block takes {size} bytes, at most {max} allowed")]
+        TooLarge { size: usize, max: usize },
+        #[error("{0}")]
+        Consensus(consensus_errors::ConsensusError),
+    }
+
+    impl ChainError {
+        /// Whether the error is the fault of the node rather than of
+        /// the block
+        pub fn is_local(&self) -> bool {
+            matches!(
+                self,
+                ChainError::Ledger(ledger_errors::LedgerError::Storage(_))
+            )
+        }
+    }
+
+    #[derive(Debug, Clone, Error)]
+    pub enum SyncError {
+        #[error("{0}")]
+        Request(node_errors::RequestError),
+        #[error("Peer sent an invalid chain: {0}")]
+        Invalid(ChainError),
+        #[error("Peer sent an unexpected response")]
+        UnexpectedResponse,
+        #[error("Peer didn't serve the requested blocks")]
+        MissingBlocks,
+    }
+}
+
+pub mod consensus_errors {
+    use super::*;
+
+    #[derive(Debug, Clone, Error)]
+    pub enum ConsensusError {
+        #[error("Block producer is not a validator")]
+        UnknownProducer,
+        #[error("Bad block signature")]
+        BadSignature,
+        #[error("Block was produced before its producer's turn")]
+        TooEarly,
+    }
 }
@@ -0,0 +1,119 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+
+/// Peers whose reports are remembered, the oldest are dropped first
+const MAX_REPORTERS: usize = 64;
+
+struct Reports {
+    listen: SocketAddr,
+    min_reports: usize,
+    /// IP every peer sees the node at, by the IP of the peer
+    observed: HashMap<IpAddr, IpAddr>,
+    order: Vec<IpAddr>,
+    current: Option<IpAddr>,
+}
+
+/// Address of the node as other nodes see it, behind a NAT it differs
+/// from the one the node listens on
+#[derive(Clone)]
+pub struct ExternalAddress {
+    inner: Arc<Mutex<Reports>>,
+}
+
+impl ExternalAddress {
+    pub fn new(listen: SocketAddr, min_reports: usize) -> ExternalAddress {
+        ExternalAddress {
+            inner: Arc::new(Mutex::new(Reports {
+                listen,
+                min_reports,
+                observed: HashMap::new(),
+                order: Vec::new(),
+                current: None,
+            })),
+        }
+    }
+
+    /// Records the IP a peer sees the node at, returns the new external
+    /// address once enough peers agree on a different one
+    pub fn report(&self, reporter: IpAddr, observed: IpAddr) -> Option<SocketAddr> {
+        let mut reports = self.inner.lock().unwrap();
+
+        if reports.observed.insert(reporter, observed).is_none() {
+            reports.order.push(reporter);
+            if reports.order.len() > MAX_REPORTERS {
+                let oldest = reports.order.remove(0);
+                reports.observed.remove(&oldest);
+            }
+        }
+
+        let mut votes: HashMap<IpAddr, usize> = HashMap::new();
+        for ip in reports.observed.values() {
+            *votes.entry(*ip).or_default() += 1;
+        }
+
+        // the current address stays on ties
+        let current_votes = reports
+            .current
+            .map_or(0, |ip| votes.get(&ip).copied().unwrap_or(0));
+        let (best, count) = votes.into_iter().max_by_key(|(_, count)| *count)?;
+        if count < reports.min_reports || count <= current_votes {
+            return None;
+        }
+
+        reports.current = Some(best);
+        Some(SocketAddr::new(best, reports.listen.port()))
+    }
+
+    /// Address other nodes can reach the node at
+    pub fn announced(&self) -> SocketAddr {
+        let reports = self.inner.lock().unwrap();
+        reports.current.map_or(reports.listen, |ip| {
+            SocketAddr::new(ip, reports.listen.port())
+        })
+    }
+}
+
+#[cfg(test)]
+mod external_tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn report_test() {
+        let external = ExternalAddress::new("10.0.0.1:5050".parse().unwrap(), 2);
+
+        // one peer, however insistent, isn't enough
+        assert!(external.report(ip("1.1.1.1"), ip("8.8.8.8")).is_none());
+        assert!(external.report(ip("1.1.1.1"), ip("8.8.8.8")).is_none());
+        assert_eq!(external.announced(), "10.0.0.1:5050".parse().unwrap());
+
+        assert_eq!(
+            external.report(ip("2.2.2.2"), ip("8.8.8.8")),
+            Some("8.8.8.8:5050".parse().unwrap())
+        );
+        assert_eq!(external.announced(), "8.8.8.8:5050".parse().unwrap());
+
+        // a minority doesn't change the address
+        assert!(external.report(ip("3.3.3.3"), ip("9.9.9.9")).is_none());
+        assert_eq!(external.announced(), "8.8.8.8:5050".parse().unwrap());
+    }
+
+    #[test]
+    fn abandoned_test() {
+        let external = ExternalAddress::new("10.0.0.1:5050".parse().unwrap(), 2);
+        external.report(ip("1.1.1.1"), ip("8.8.8.8"));
+        external.report(ip("2.2.2.2"), ip("8.8.8.8"));
+
+        // the current address loses every vote
+        assert!(external.report(ip("1.1.1.1"), ip("9.9.9.9")).is_none());
+        assert_eq!(
+            external.report(ip("2.2.2.2"), ip("9.9.9.9")),
+            Some("9.9.9.9:5050".parse().unwrap())
+        );
+        assert_eq!(external.announced(), "9.9.9.9:5050".parse().unwrap());
+    }
+}
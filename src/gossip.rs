@@ -0,0 +1,145 @@
+use crate::models::packet_models;
+use std::collections::{HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+
+/// Amount of packet ids remembered to stop gossip from looping
+const SEEN_CACHE_SIZE: usize = 10000;
+
+/// Packet which has to be forwarded to every session except the one it came from
+#[derive(Clone, Debug)]
+pub struct Propagated {
+    pub origin: u64,
+    pub packet: packet_models::Packet,
+}
+
+/// Bounded set of packet ids, the oldest ids are forgotten first
+pub struct SeenCache {
+    ids: HashSet<u64>,
+    order: VecDeque<u64>,
+    capacity: usize,
+}
+
+impl SeenCache {
+    pub fn new(capacity: usize) -> SeenCache {
+        SeenCache {
+            ids: HashSet::with_capacity(capacity),
+            order: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn contains(&self, id: u64) -> bool {
+        self.ids.contains(&id)
+    }
+
+    /// Returns true if the id wasn't seen before
+    pub fn insert(&mut self, id: u64) -> bool {
+        if !self.ids.insert(id) {
+            return false;
+        }
+
+        self.order.push_back(id);
+        if self.order.len() > self.capacity {
+            if let Some(old) = self.order.pop_front() {
+                self.ids.remove(&old);
+            }
+        }
+
+        true
+    }
+}
+
+/// Broadcast channel shared by all sessions, each packet id goes through it once
+#[derive(Clone)]
+pub struct Gossip {
+    tx: broadcast::Sender<Propagated>,
+    seen: Arc<Mutex<SeenCache>>,
+}
+
+impl Gossip {
+    pub fn new(capacity: usize) -> Gossip {
+        let (tx, _) = broadcast::channel(capacity);
+        Gossip {
+            tx,
+            seen: Arc::new(Mutex::new(SeenCache::new(SEEN_CACHE_SIZE))),
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Propagated> {
+        self.tx.subscribe()
+    }
+
+    /// Whether the id already went through the node
+    pub fn is_seen(&self, id: u64) -> bool {
+        self.seen.lock().unwrap().contains(id)
+    }
+
+    /// Remembers the id without forwarding anything, returns true if it is new
+    pub fn mark_seen(&self, id: u64) -> bool {
+        self.seen.lock().unwrap().insert(id)
+    }
+
+    /// Forwards the packet to every other session, its id has to be marked
+    /// as seen already
+    pub fn forward(&self, origin: u64, packet: packet_models::Packet) {
+        // no sessions are subscribed, nobody to forward to
+        let _ = self.tx.send(Propagated { origin, packet });
+    }
+
+    /// Forwards the packet to every other session if its id wasn't seen yet,
+    /// returns false for packets that already went through the node
+    pub fn publish(&self, origin: u64, id: u64, packet: packet_models::Packet) -> bool {
+        if !self.mark_seen(id) {
+            return false;
+        }
+
+        self.forward(origin, packet);
+        true
+    }
+}
+
+#[cfg(test)]
+mod gossip_tests {
+    use super::*;
+
+    fn announce(id: u64) -> packet_models::Packet {
+        packet_models::Packet::request(packet_models::Request::announce(
+            packet_models::AnnounceRequest {
+                id,
+                addr: vec![1, 2, 3, 4, 0, 255],
+                identity: None,
+            },
+        ))
+    }
+
+    #[test]
+    fn seen_cache_test() {
+        let mut cache = SeenCache::new(2);
+
+        assert!(cache.insert(1));
+        assert!(!cache.insert(1));
+        assert!(cache.insert(2));
+        assert!(cache.insert(3));
+
+        // 1 was evicted as the oldest id
+        assert!(cache.insert(1));
+        assert!(!cache.insert(3));
+    }
+
+    #[test]
+    fn publish_once_test() {
+        let gossip = Gossip::new(10);
+        let mut rx = gossip.subscribe();
+
+        assert!(!gossip.is_seen(42));
+        assert!(gossip.publish(1, 42, announce(42)));
+        assert!(gossip.is_seen(42));
+        assert!(!gossip.publish(2, 42, announce(42)));
+
+        let propagated = rx.try_recv().unwrap();
+        assert_eq!(propagated.origin, 1);
+        assert_eq!(propagated.packet, announce(42));
+        assert!(rx.try_recv().is_err());
+    }
+}
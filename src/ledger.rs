@@ -0,0 +1,702 @@
+use crate::errors::ledger_errors::LedgerError;
+use crate::models::block_models::{Block, Header};
+use crate::models::transaction_models::Transaction;
+use redb::{
+    Database, MultimapTableDefinition, ReadableTable, ReadableTableMetadata, TableDefinition,
+    WriteTransaction,
+};
+use std::path::Path;
+use std::sync::Arc;
+
+/// Balance and nonce of every account, by public key
+const ACCOUNTS: TableDefinition<&[u8], (u64, u64)> = TableDefinition::new("accounts");
+/// Canonically encoded transactions by id
+const TRANSACTIONS: TableDefinition<&[u8], &[u8]> = TableDefinition::new("transactions");
+/// Ids of the transactions an account sent or received
+const BY_ADDRESS: MultimapTableDefinition<&[u8], &[u8]> =
+    MultimapTableDefinition::new("transactions_by_address");
+/// Encoded headers by block id
+const HEADERS: TableDefinition<&[u8], &[u8]> = TableDefinition::new("headers");
+/// Encoded blocks by id
+const BLOCKS: TableDefinition<&[u8], &[u8]> = TableDefinition::new("blocks");
+/// Ids of the blocks of the main chain by height
+const MAIN_CHAIN: TableDefinition<u64, &[u8]> = TableDefinition::new("main_chain");
+/// Weight of the branch ending with the block, for every known header
+const WEIGHTS: TableDefinition<&[u8], u128> = TableDefinition::new("weights");
+/// Main chain block and position in it of every applied transaction
+const LOCATIONS: TableDefinition<&[u8], (&[u8], u64)> =
+    TableDefinition::new("transaction_locations");
+
+/// State of an account
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Account {
+    pub balance: u64,
+    /// amount of transactions the account sent
+    pub nonce: u64,
+}
+
+fn storage(e: impl std::fmt::Display) -> LedgerError {
+    LedgerError::Storage(e.to_string())
+}
+
+fn credit_in(tx: &WriteTransaction, address: &[u8], amount: u64) -> Result<(), LedgerError> {
+    let mut accounts = tx.open_table(ACCOUNTS).map_err(storage)?;
+    let (balance, nonce) = accounts
+        .get(address)
+        .map_err(storage)?
+        .map_or((0, 0), |a| a.value());
+    let balance = balance.checked_add(amount).ok_or(LedgerError::Overflow)?;
+    accounts
+        .insert(address, (balance, nonce))
+        .map_err(storage)?;
+    Ok(())
+}
+
+/// Moves the coins of a valid transaction, the fee leaves the sender's
+/// balance along with the amount
+fn apply_in(tx: &WriteTransaction, transaction: &Transaction) -> Result<(), LedgerError> {
+    transaction.verify().map_err(LedgerError::Invalid)?;
+    let id = transaction.id();
+
+    let mut transactions = tx.open_table(TRANSACTIONS).map_err(storage)?;
+    if transactions.get(id.as_slice()).map_err(storage)?.is_some() {
+        return Err(LedgerError::AlreadyApplied);
+    }
+
+    let mut accounts = tx.open_table(ACCOUNTS).map_err(storage)?;
+    let sender = accounts
+        .get(transaction.sender.as_slice())
+        .map_err(storage)?
+        .map_or((0, 0), |a| a.value());
+    let (balance, nonce) = sender;
+
+    if transaction.nonce != nonce {
+        return Err(LedgerError::BadNonce {
+            expected: nonce,
+            got: transaction.nonce,
+        });
+    }
+    let spent = transaction
+        .amount
+        .checked_add(transaction.fee)
+        .ok_or(LedgerError::Overflow)?;
+    let balance = balance
+        .checked_sub(spent)
+        .ok_or(LedgerError::InsufficientFunds)?;
+    accounts
+        .insert(transaction.sender.as_slice(), (balance, nonce + 1))
+        .map_err(storage)?;
+
+    let (balance, nonce) = accounts
+        .get(transaction.recipient.as_slice())
+        .map_err(storage)?
+        .map_or((0, 0), |a| a.value());
+    let balance = balance
+        .checked_add(transaction.amount)
+        .ok_or(LedgerError::Overflow)?;
+    accounts
+        .insert(transaction.recipient.as_slice(), (balance, nonce))
+        .map_err(storage)?;
+
+    transactions
+        .insert(id.as_slice(), transaction.encode().as_slice())
+        .map_err(storage)?;
+
+    let mut by_address = tx.open_multimap_table(BY_ADDRESS).map_err(storage)?;
+    by_address
+        .insert(transaction.sender.as_slice(), id.as_slice())
+        .map_err(storage)?;
+    by_address
+        .insert(transaction.recipient.as_slice(), id.as_slice())
+        .map_err(storage)?;
+
+    Ok(())
+}
+
+/// Takes back a transaction of the last block of the main chain
+fn revert_in(tx: &WriteTransaction, transaction: &Transaction) -> Result<(), LedgerError> {
+    let id = transaction.id();
+    let mut accounts = tx.open_table(ACCOUNTS).map_err(storage)?;
+
+    let (balance, nonce) = accounts
+        .get(transaction.recipient.as_slice())
+        .map_err(storage)?
+        .map_or((0, 0), |a| a.value());
+    let balance = balance
+        .checked_sub(transaction.amount)
+        .ok_or(LedgerError::Storage(
+            "Reverted balance underflow".to_string(),
+        ))?;
+    accounts
+        .insert(transaction.recipient.as_slice(), (balance, nonce))
+        .map_err(storage)?;
+
+    let (balance, nonce) = accounts
+        .get(transaction.sender.as_slice())
+        .map_err(storage)?
+        .map_or((0, 0), |a| a.value());
+    if nonce != transaction.nonce + 1 {
+        return Err(LedgerError::Storage(
+            "Reverted transaction isn't the last one of its sender".to_string(),
+        ));
+    }
+    let balance = balance
+        .checked_add(transaction.amount + transaction.fee)
+        .ok_or(LedgerError::Overflow)?;
+    accounts
+        .insert(transaction.sender.as_slice(), (balance, transaction.nonce))
+        .map_err(storage)?;
+
+    tx.open_table(TRANSACTIONS)
+        .map_err(storage)?
+        .remove(id.as_slice())
+        .map_err(storage)?;
+    let mut by_address = tx.open_multimap_table(BY_ADDRESS).map_err(storage)?;
+    by_address
+        .remove(transaction.sender.as_slice(), id.as_slice())
+        .map_err(storage)?;
+    by_address
+        .remove(transaction.recipient.as_slice(), id.as_slice())
+        .map_err(storage)?;
+
+    Ok(())
+}
+
+/// Stores the header of a block of any branch
+fn store_header(tx: &WriteTransaction, header: &Header, weight: u128) -> Result<(), LedgerError> {
+    let id = header.id();
+    let encoded = rmp_serde::to_vec(header).map_err(storage)?;
+
+    tx.open_table(HEADERS)
+        .map_err(storage)?
+        .insert(id.as_slice(), encoded.as_slice())
+        .map_err(storage)?;
+    tx.open_table(WEIGHTS)
+        .map_err(storage)?
+        .insert(id.as_slice(), weight)
+        .map_err(storage)?;
+
+    Ok(())
+}
+
+/// Stores the block as the next one of the main chain
+fn store_block(tx: &WriteTransaction, block: &Block, weight: u128) -> Result<(), LedgerError> {
+    let id = block.id();
+    let encoded = rmp_serde::to_vec(block).map_err(storage)?;
+
+    store_header(tx, &block.header, weight)?;
+    tx.open_table(BLOCKS)
+        .map_err(storage)?
+        .insert(id.as_slice(), encoded.as_slice())
+        .map_err(storage)?;
+    tx.open_table(MAIN_CHAIN)
+        .map_err(storage)?
+        .insert(block.header.height, id.as_slice())
+        .map_err(storage)?;
+
+    let mut locations = tx.open_table(LOCATIONS).map_err(storage)?;
+    for (index, transaction) in block.transactions.iter().enumerate() {
+        locations
+            .insert(transaction.id().as_slice(), (id.as_slice(), index as u64))
+            .map_err(storage)?;
+    }
+
+    Ok(())
+}
+
+/// Balances and applied transactions, every change is a single redb
+/// transaction, so a crash never leaves the ledger half updated
+#[derive(Clone)]
+pub struct Ledger {
+    db: Arc<Database>,
+}
+
+impl Ledger {
+    pub fn open(path: impl AsRef<Path>) -> Result<Ledger, LedgerError> {
+        Ledger::init(Database::create(path).map_err(storage)?)
+    }
+
+    #[cfg(test)]
+    pub fn in_memory() -> Ledger {
+        let db = Database::builder()
+            .create_with_backend(redb::backends::InMemoryBackend::new())
+            .unwrap();
+        Ledger::init(db).unwrap()
+    }
+
+    /// Creates the tables, so that reads of a fresh ledger find them
+    fn init(db: Database) -> Result<Ledger, LedgerError> {
+        let tx = db.begin_write().map_err(storage)?;
+        tx.open_table(ACCOUNTS).map_err(storage)?;
+        tx.open_table(TRANSACTIONS).map_err(storage)?;
+        tx.open_multimap_table(BY_ADDRESS).map_err(storage)?;
+        tx.open_table(HEADERS).map_err(storage)?;
+        tx.open_table(BLOCKS).map_err(storage)?;
+        tx.open_table(MAIN_CHAIN).map_err(storage)?;
+        tx.open_table(WEIGHTS).map_err(storage)?;
+        tx.open_table(LOCATIONS).map_err(storage)?;
+        tx.commit().map_err(storage)?;
+
+        Ok(Ledger { db: Arc::new(db) })
+    }
+
+    pub fn account(&self, address: &[u8; 32]) -> Result<Option<Account>, LedgerError> {
+        let tx = self.db.begin_read().map_err(storage)?;
+        let table = tx.open_table(ACCOUNTS).map_err(storage)?;
+        let account = table.get(address.as_slice()).map_err(storage)?;
+
+        Ok(account.map(|a| {
+            let (balance, nonce) = a.value();
+            Account { balance, nonce }
+        }))
+    }
+
+    pub fn transaction(&self, id: &[u8; 32]) -> Result<Option<Transaction>, LedgerError> {
+        let tx = self.db.begin_read().map_err(storage)?;
+        let table = tx.open_table(TRANSACTIONS).map_err(storage)?;
+        let Some(encoded) = table.get(id.as_slice()).map_err(storage)? else {
+            return Ok(None);
+        };
+
+        Transaction::decode(encoded.value())
+            .map(Some)
+            .map_err(storage)
+    }
+
+    /// Id of the main chain block including the transaction and the
+    /// position of the transaction in it
+    pub fn location(&self, id: &[u8; 32]) -> Result<Option<([u8; 32], u64)>, LedgerError> {
+        let tx = self.db.begin_read().map_err(storage)?;
+        let table = tx.open_table(LOCATIONS).map_err(storage)?;
+        let Some(location) = table.get(id.as_slice()).map_err(storage)? else {
+            return Ok(None);
+        };
+
+        let (block, index) = location.value();
+        let block = <[u8; 32]>::try_from(block).map_err(storage)?;
+        Ok(Some((block, index)))
+    }
+
+    /// Ids of the transactions the account sent or received, `max` at most
+    pub fn transactions_of(
+        &self,
+        address: &[u8; 32],
+        max: usize,
+    ) -> Result<Vec<[u8; 32]>, LedgerError> {
+        let tx = self.db.begin_read().map_err(storage)?;
+        let table = tx.open_multimap_table(BY_ADDRESS).map_err(storage)?;
+
+        let mut ids = Vec::new();
+        for id in table.get(address.as_slice()).map_err(storage)?.take(max) {
+            let id = id.map_err(storage)?;
+            if let Ok(id) = id.value().try_into() {
+                ids.push(id);
+            }
+        }
+
+        Ok(ids)
+    }
+
+    /// Header of the last block of the main chain
+    pub fn tip(&self) -> Result<Option<Header>, LedgerError> {
+        let tx = self.db.begin_read().map_err(storage)?;
+        let chain = tx.open_table(MAIN_CHAIN).map_err(storage)?;
+        let Some((_, id)) = chain.last().map_err(storage)? else {
+            return Ok(None);
+        };
+        let Ok(id) = id.value().try_into() else {
+            return Err(LedgerError::Storage("Bad block id".to_string()));
+        };
+
+        self.header(&id)?
+            .ok_or(LedgerError::Storage("Missing tip header".to_string()))
+            .map(Some)
+    }
+
+    /// Id of the main chain block at the height
+    pub fn block_id_at(&self, height: u64) -> Result<Option<[u8; 32]>, LedgerError> {
+        let tx = self.db.begin_read().map_err(storage)?;
+        let chain = tx.open_table(MAIN_CHAIN).map_err(storage)?;
+        let id = chain.get(height).map_err(storage)?;
+
+        Ok(id.and_then(|id| id.value().try_into().ok()))
+    }
+
+    pub fn header(&self, id: &[u8; 32]) -> Result<Option<Header>, LedgerError> {
+        let tx = self.db.begin_read().map_err(storage)?;
+        let table = tx.open_table(HEADERS).map_err(storage)?;
+        let Some(encoded) = table.get(id.as_slice()).map_err(storage)? else {
+            return Ok(None);
+        };
+
+        rmp_serde::from_slice(encoded.value())
+            .map(Some)
+            .map_err(storage)
+    }
+
+    pub fn block(&self, id: &[u8; 32]) -> Result<Option<Block>, LedgerError> {
+        let tx = self.db.begin_read().map_err(storage)?;
+        let table = tx.open_table(BLOCKS).map_err(storage)?;
+        let Some(encoded) = table.get(id.as_slice()).map_err(storage)? else {
+            return Ok(None);
+        };
+
+        rmp_serde::from_slice(encoded.value())
+            .map(Some)
+            .map_err(storage)
+    }
+
+    /// Credits the initial allocations and stores the genesis block,
+    /// unless the chain was already started
+    pub fn init_genesis(
+        &self,
+        genesis: &Block,
+        allocations: &[([u8; 32], u64)],
+    ) -> Result<(), LedgerError> {
+        let tx = self.db.begin_write().map_err(storage)?;
+        if !tx
+            .open_table(MAIN_CHAIN)
+            .map_err(storage)?
+            .is_empty()
+            .map_err(storage)?
+        {
+            return Ok(());
+        }
+
+        for (address, amount) in allocations {
+            credit_in(&tx, address, *amount)?;
+        }
+        store_block(&tx, genesis, 0)?;
+        tx.commit().map_err(storage)
+    }
+
+    /// Weight of the branch ending with the block
+    pub fn weight(&self, id: &[u8; 32]) -> Result<Option<u128>, LedgerError> {
+        let tx = self.db.begin_read().map_err(storage)?;
+        let table = tx.open_table(WEIGHTS).map_err(storage)?;
+        let weight = table.get(id.as_slice()).map_err(storage)?;
+
+        Ok(weight.map(|w| w.value()))
+    }
+
+    /// Keeps headers of a branch the node doesn't follow
+    pub fn track(&self, headers: &[(Header, u128)]) -> Result<(), LedgerError> {
+        let tx = self.db.begin_write().map_err(storage)?;
+        for (header, weight) in headers {
+            store_header(&tx, header, *weight)?;
+        }
+        tx.commit().map_err(storage)
+    }
+
+    /// Applies the transactions of the block and makes it the tip of the
+    /// main chain, a block with an invalid transaction changes nothing
+    pub fn apply_block(&self, block: &Block, weight: u128) -> Result<(), LedgerError> {
+        let tx = self.db.begin_write().map_err(storage)?;
+        Ledger::extend_in(&tx, block, weight)?;
+        tx.commit().map_err(storage)
+    }
+
+    fn extend_in(tx: &WriteTransaction, block: &Block, weight: u128) -> Result<(), LedgerError> {
+        {
+            let chain = tx.open_table(MAIN_CHAIN).map_err(storage)?;
+            let tip = chain.last().map_err(storage)?;
+            let extends = tip.is_some_and(|(height, id)| {
+                height.value() + 1 == block.header.height && id.value() == block.header.parent
+            });
+            if !extends {
+                return Err(LedgerError::NotExtendingTip);
+            }
+        }
+
+        for transaction in &block.transactions {
+            apply_in(tx, transaction)?;
+        }
+        store_block(tx, block, weight)
+    }
+
+    /// Reverts the main chain down to the ancestor and applies the blocks
+    /// of another branch on top of it, all or nothing. Reverted blocks
+    /// stay stored and are returned oldest first.
+    pub fn reorganize(
+        &self,
+        ancestor: &Header,
+        blocks: &[(Block, u128)],
+    ) -> Result<Vec<Block>, LedgerError> {
+        let tx = self.db.begin_write().map_err(storage)?;
+        let mut reverted = Vec::new();
+
+        loop {
+            let (height, id) = {
+                let chain = tx.open_table(MAIN_CHAIN).map_err(storage)?;
+                let Some((height, id)) = chain.last().map_err(storage)? else {
+                    return Err(LedgerError::NotExtendingTip);
+                };
+                (height.value(), id.value().to_vec())
+            };
+            if height <= ancestor.height {
+                if id != ancestor.id() {
+                    return Err(LedgerError::NotExtendingTip);
+                }
+                break;
+            }
+
+            let block: Block = {
+                let table = tx.open_table(BLOCKS).map_err(storage)?;
+                let encoded = table
+                    .get(id.as_slice())
+                    .map_err(storage)?
+                    .ok_or(LedgerError::Storage("Missing main chain block".to_string()))?;
+                rmp_serde::from_slice(encoded.value()).map_err(storage)?
+            };
+            let mut locations = tx.open_table(LOCATIONS).map_err(storage)?;
+            for transaction in block.transactions.iter().rev() {
+                revert_in(&tx, transaction)?;
+                locations
+                    .remove(transaction.id().as_slice())
+                    .map_err(storage)?;
+            }
+            drop(locations);
+            tx.open_table(MAIN_CHAIN)
+                .map_err(storage)?
+                .remove(height)
+                .map_err(storage)?;
+            reverted.push(block);
+        }
+
+        for (block, weight) in blocks {
+            Ledger::extend_in(&tx, block, *weight)?;
+        }
+        tx.commit().map_err(storage)?;
+
+        reverted.reverse();
+        Ok(reverted)
+    }
+
+    /// Adds coins to an account, e.g. from the genesis allocation
+    #[cfg(test)]
+    pub fn credit(&self, address: &[u8; 32], amount: u64) -> Result<(), LedgerError> {
+        let tx = self.db.begin_write().map_err(storage)?;
+        credit_in(&tx, address, amount)?;
+        tx.commit().map_err(storage)
+    }
+
+    /// Applies a single transaction outside of a block
+    #[cfg(test)]
+    pub fn apply(&self, transaction: &Transaction) -> Result<(), LedgerError> {
+        let tx = self.db.begin_write().map_err(storage)?;
+        apply_in(&tx, transaction)?;
+        tx.commit().map_err(storage)
+    }
+}
+
+#[cfg(test)]
+mod ledger_tests {
+    use super::*;
+    use ed25519_dalek::SigningKey;
+    use rand_core::OsRng;
+
+    #[test]
+    fn apply_test() {
+        let ledger = Ledger::in_memory();
+        let key = SigningKey::generate(&mut OsRng);
+        let sender = key.verifying_key().to_bytes();
+        ledger.credit(&sender, 100).unwrap();
+
+        let tx = Transaction::new_signed(&key, [2; 32], 60, 5, 0);
+        ledger.apply(&tx).unwrap();
+
+        assert_eq!(
+            ledger.account(&sender).unwrap(),
+            Some(Account {
+                balance: 35,
+                nonce: 1
+            })
+        );
+        assert_eq!(ledger.account(&[2; 32]).unwrap().unwrap().balance, 60);
+        assert_eq!(ledger.transaction(&tx.id()).unwrap(), Some(tx.clone()));
+        assert_eq!(ledger.transactions_of(&[2; 32], 10).unwrap(), vec![tx.id()]);
+        assert_eq!(ledger.transactions_of(&sender, 10).unwrap(), vec![tx.id()]);
+        assert!(ledger.transactions_of(&sender, 0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejected_test() {
+        let ledger = Ledger::in_memory();
+        let key = SigningKey::generate(&mut OsRng);
+        ledger.credit(&key.verifying_key().to_bytes(), 100).unwrap();
+
+        let tx = Transaction::new_signed(&key, [2; 32], 60, 5, 0);
+        ledger.apply(&tx).unwrap();
+        assert!(matches!(
+            ledger.apply(&tx),
+            Err(LedgerError::AlreadyApplied)
+        ));
+
+        let replay = Transaction::new_signed(&key, [2; 32], 1, 1, 0);
+        assert!(matches!(
+            ledger.apply(&replay),
+            Err(LedgerError::BadNonce {
+                expected: 1,
+                got: 0
+            })
+        ));
+
+        // a failed transaction changes nothing
+        let too_much = Transaction::new_signed(&key, [2; 32], 40, 0, 1);
+        assert!(matches!(
+            ledger.apply(&too_much),
+            Err(LedgerError::InsufficientFunds)
+        ));
+        assert_eq!(ledger.account(&[2; 32]).unwrap().unwrap().balance, 60);
+        assert!(ledger.transaction(&too_much.id()).unwrap().is_none());
+    }
+
+    fn block(parent: &Header, transactions: Vec<Transaction>) -> Block {
+        Block {
+            header: Header {
+                parent: parent.id().to_vec(),
+                height: parent.height + 1,
+                timestamp: parent.timestamp + 1,
+                transactions_root: Block::transactions_root(&transactions).to_vec(),
+                producer: Vec::new(),
+                signature: Vec::new(),
+            },
+            transactions,
+        }
+    }
+
+    fn genesis() -> Block {
+        Block {
+            header: Header {
+                parent: vec![0; 32],
+                height: 0,
+                timestamp: 0,
+                transactions_root: Block::transactions_root(&[]).to_vec(),
+                producer: Vec::new(),
+                signature: Vec::new(),
+            },
+            transactions: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn apply_block_test() {
+        let ledger = Ledger::in_memory();
+        let key = SigningKey::generate(&mut OsRng);
+        let sender = key.verifying_key().to_bytes();
+        let genesis = genesis();
+        ledger.init_genesis(&genesis, &[(sender, 100)]).unwrap();
+        // a started chain is left as it is
+        ledger.init_genesis(&genesis, &[(sender, 100)]).unwrap();
+        assert_eq!(ledger.tip().unwrap(), Some(genesis.header.clone()));
+
+        let first = block(
+            &genesis.header,
+            vec![Transaction::new_signed(&key, [2; 32], 60, 5, 0)],
+        );
+        ledger.apply_block(&first, 1).unwrap();
+        assert_eq!(ledger.tip().unwrap(), Some(first.header.clone()));
+        assert_eq!(ledger.block_id_at(1).unwrap(), Some(first.id()));
+        assert_eq!(ledger.block(&first.id()).unwrap(), Some(first.clone()));
+        assert_eq!(ledger.account(&sender).unwrap().unwrap().balance, 35);
+        assert_eq!(
+            ledger.location(&first.transactions[0].id()).unwrap(),
+            Some((first.id(), 0))
+        );
+
+        // the valid first transaction is rolled back along with the bad one
+        let bad = block(
+            &first.header,
+            vec![
+                Transaction::new_signed(&key, [2; 32], 10, 5, 1),
+                Transaction::new_signed(&key, [2; 32], 100, 5, 2),
+            ],
+        );
+        assert!(matches!(
+            ledger.apply_block(&bad, 2),
+            Err(LedgerError::InsufficientFunds)
+        ));
+        assert_eq!(ledger.account(&sender).unwrap().unwrap().balance, 35);
+        assert_eq!(ledger.tip().unwrap(), Some(first.header.clone()));
+
+        assert!(matches!(
+            ledger.apply_block(&block(&genesis.header, Vec::new()), 1),
+            Err(LedgerError::NotExtendingTip)
+        ));
+    }
+
+    #[test]
+    fn reorganize_test() {
+        let ledger = Ledger::in_memory();
+        let key = SigningKey::generate(&mut OsRng);
+        let sender = key.verifying_key().to_bytes();
+        let genesis = genesis();
+        ledger.init_genesis(&genesis, &[(sender, 100)]).unwrap();
+
+        let paid = Transaction::new_signed(&key, [2; 32], 60, 5, 0);
+        let first = block(&genesis.header, vec![paid.clone()]);
+        let second = block(
+            &first.header,
+            vec![Transaction::new_signed(&key, [3; 32], 10, 5, 1)],
+        );
+        ledger.apply_block(&first, 1).unwrap();
+        ledger.apply_block(&second, 2).unwrap();
+
+        // the other branch pays somebody else with the same nonce
+        let other = block(
+            &genesis.header,
+            vec![Transaction::new_signed(&key, [4; 32], 30, 5, 0)],
+        );
+        let reverted = ledger
+            .reorganize(&genesis.header, &[(other.clone(), 3)])
+            .unwrap();
+        assert_eq!(reverted, vec![first.clone(), second]);
+
+        assert_eq!(ledger.tip().unwrap(), Some(other.header.clone()));
+        assert_eq!(ledger.weight(&other.id()).unwrap(), Some(3));
+        assert_eq!(
+            ledger.account(&sender).unwrap(),
+            Some(Account {
+                balance: 65,
+                nonce: 1
+            })
+        );
+        assert_eq!(ledger.account(&[2; 32]).unwrap().unwrap().balance, 0);
+        assert!(ledger.transaction(&paid.id()).unwrap().is_none());
+        assert!(ledger.location(&paid.id()).unwrap().is_none());
+        assert!(ledger.transactions_of(&[2; 32], 10).unwrap().is_empty());
+        // reverted blocks stay known
+        assert!(ledger.block(&first.id()).unwrap().is_some());
+
+        // a failing branch leaves the main chain as it was
+        let bad = block(
+            &genesis.header,
+            vec![Transaction::new_signed(&key, [4; 32], 300, 5, 0)],
+        );
+        assert!(matches!(
+            ledger.reorganize(&genesis.header, &[(bad, 9)]),
+            Err(LedgerError::InsufficientFunds)
+        ));
+        assert_eq!(ledger.tip().unwrap(), Some(other.header));
+        assert_eq!(ledger.account(&sender).unwrap().unwrap().balance, 65);
+    }
+
+    #[test]
+    fn persistence_test() {
+        let dir = std::env::temp_dir().join(format!("ledger-test-{}", rand::random::<u64>()));
+        let key = SigningKey::generate(&mut OsRng);
+        let sender = key.verifying_key().to_bytes();
+        let tx = Transaction::new_signed(&key, [2; 32], 10, 0, 0);
+
+        {
+            let ledger = Ledger::open(&dir).unwrap();
+            ledger.credit(&sender, 10).unwrap();
+            ledger.apply(&tx).unwrap();
+        }
+
+        let ledger = Ledger::open(&dir).unwrap();
+        assert_eq!(ledger.account(&sender).unwrap().unwrap().nonce, 1);
+        assert!(ledger.transaction(&tx.id()).unwrap().is_some());
+        std::fs::remove_file(&dir).unwrap();
+    }
+}
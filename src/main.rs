@@ -1,13 +1,25 @@
+mod address_book;
+mod bans;
+mod chain;
 mod errors;
+mod external;
+mod gossip;
+mod ledger;
+mod mempool;
 mod models;
 mod node;
+mod rate_limit;
+mod registry;
+mod requests;
+mod session;
+mod slots;
 #[macro_use]
 mod tools;
 mod config;
+mod connections;
+mod consensus;
 
-use std::collections::HashSet;
 use std::net::SocketAddr;
-use std::sync::{Arc, Mutex};
 use tokio::signal;
 use tokio::sync::broadcast;
 use tokio::time::{sleep, Duration};
@@ -19,10 +31,10 @@ async fn main() -> errors::ResultSmall<()> {
 
     // configure channels
     let (tx, mut rx) = broadcast::channel::<u8>(1);
-    let (txp, _) = broadcast::channel::<models::packet_models::Packet>(100);
+    let txp = gossip::Gossip::new(100);
     let (new_peers_tx, _) = broadcast::channel::<SocketAddr>(100);
 
-    let peers: Arc<Mutex<HashSet<SocketAddr>>> = Arc::new(Mutex::new(HashSet::with_capacity(100)));
+    let peers = address_book::AddressBook::new();
 
     match node::load_peers(peers.clone()) {
         Ok(_) => {
@@ -33,14 +45,90 @@ async fn main() -> errors::ResultSmall<()> {
         }
     }
 
+    // the node must not dial itself
+    peers.mark_own(*config::SERVER_ADDRESS);
+
+    let bans = bans::BanList::new(bans::Policy {
+        threshold: *config::BAN_THRESHOLD,
+        duration: *config::BAN_DURATION,
+        permanent_after: *config::PERMANENT_BAN_AFTER,
+        whitelist: config::WHITELIST.clone(),
+    });
+
+    if let Err(e) = node::load_bans(bans.clone()) {
+        println!("Failed to load bans from the file, due to: {}", e);
+    }
+
+    println!(
+        "Node identity: {}",
+        tools::to_hex(node::identity().as_bytes())
+    );
+
+    let ledger = ledger::Ledger::open(&*config::LEDGER_PATH)?;
+    let mempool = mempool::Mempool::new(mempool::Limits {
+        max_size: *config::MEMPOOL_SIZE,
+        max_per_sender: *config::MEMPOOL_PER_SENDER,
+        min_fee: *config::MIN_FEE,
+        expiry: *config::MEMPOOL_EXPIRY,
+    });
+    let validator_key = node::load_validator_key()?;
+    let is_validator = validator_key.is_some();
+    if let Some(key) = &validator_key {
+        println!(
+            "Validator key: {}",
+            tools::to_hex(key.verifying_key().as_bytes())
+        );
+    }
+    let consensus = consensus::ProofOfAuthority::new(
+        config::VALIDATORS.clone(),
+        *config::BLOCK_INTERVAL,
+        validator_key,
+    );
+    let chain = chain::Chain::open(
+        ledger.clone(),
+        mempool.clone(),
+        std::sync::Arc::new(consensus),
+        &config::GENESIS_ALLOCATIONS,
+        *config::GENESIS_TIMESTAMP,
+    )?;
+    println!("Chain tip: block {}", chain.tip()?.height);
+
     println!("Starting the node...");
 
     // starting main tasks
-    let fut = node::start(peers.clone(), tx.clone(), txp.clone(), new_peers_tx.clone());
-    tokio::spawn(fut);
+    let shared = node::Shared {
+        shutdown: tx.clone(),
+        book: peers.clone(),
+        bans: bans.clone(),
+        ledger,
+        mempool,
+        chain,
+        external: external::ExternalAddress::new(
+            *config::SERVER_ADDRESS,
+            *config::EXTERNAL_ADDRESS_REPORTS,
+        ),
+        propagate: txp,
+        new_peers_tx,
+        registry: registry::SessionRegistry::new(
+            node::identity().to_bytes(),
+            *config::PING_INTERVAL + *node::REQUEST_TIMEOUT,
+        ),
+        slots: slots::Slots::new(slots::Limits {
+            max_inbound: *config::MAX_INBOUND,
+            max_outbound: *config::MAX_OUTBOUND,
+            max_per_ip: *config::MAX_PER_IP,
+            max_per_subnet: *config::MAX_PER_SUBNET,
+            reserved: *config::RESERVED_SLOTS,
+            whitelist: config::WHITELIST.clone(),
+        }),
+    };
 
-    let fut = node::connect_new_peers(tx.clone(), peers.clone(), txp.clone(), new_peers_tx);
-    tokio::spawn(fut);
+    tokio::spawn(node::start(shared.clone()));
+    if is_validator {
+        tokio::spawn(node::produce_blocks(shared.clone()));
+    }
+    tokio::spawn(node::log_reorgs(shared.clone()));
+    tokio::spawn(node::manage_connections(shared));
 
     // giving the node the time to subscribe
     sleep(Duration::from_millis(500)).await;
@@ -64,5 +152,9 @@ async fn main() -> errors::ResultSmall<()> {
         }
     }
 
+    if let Err(e) = node::dump_bans(bans) {
+        println!("Failed to dump bans to the file, due to: {}", e);
+    }
+
     Ok(())
 }
@@ -0,0 +1,506 @@
+use crate::errors::mempool_errors::MempoolError;
+use crate::ledger::Account;
+use crate::models::transaction_models::Transaction;
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+/// Mempool settings of the node
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    /// transactions kept at most
+    pub max_size: usize,
+    /// pending transactions of a single sender kept at most
+    pub max_per_sender: usize,
+    pub min_fee: u64,
+    /// seconds a transaction is kept for
+    pub expiry: u64,
+}
+
+struct Entry {
+    transaction: Transaction,
+    sender: [u8; 32],
+    added: u64,
+}
+
+struct Pool {
+    limits: Limits,
+    entries: HashMap<[u8; 32], Entry>,
+    /// ids of the pending transactions of every sender, by nonce
+    by_sender: HashMap<[u8; 32], BTreeMap<u64, [u8; 32]>>,
+}
+
+impl Pool {
+    /// Removes the transaction and the later ones of its sender,
+    /// they can't be applied without it
+    fn remove_from(&mut self, sender: &[u8; 32], nonce: u64) {
+        let Some(pending) = self.by_sender.get_mut(sender) else {
+            return;
+        };
+
+        for (_, id) in pending.split_off(&nonce) {
+            self.entries.remove(&id);
+        }
+        if pending.is_empty() {
+            self.by_sender.remove(sender);
+        }
+    }
+
+    fn expire(&mut self, now: u64) {
+        let expired: Vec<([u8; 32], u64)> = self
+            .entries
+            .values()
+            .filter(|e| now.saturating_sub(e.added) >= self.limits.expiry)
+            .map(|e| (e.sender, e.transaction.nonce))
+            .collect();
+
+        for (sender, nonce) in expired {
+            self.remove_from(&sender, nonce);
+        }
+    }
+
+    /// Last transaction of a sender with the lowest fee, evicting it
+    /// doesn't leave a gap in the nonces of anyone
+    fn eviction_candidate(&self, except: &[u8; 32]) -> Option<(&[u8; 32], &Entry)> {
+        self.by_sender
+            .iter()
+            .filter(|(sender, _)| *sender != except)
+            .filter_map(|(sender, pending)| {
+                let (_, id) = pending.last_key_value()?;
+                Some((sender, self.entries.get(id)?))
+            })
+            .min_by_key(|(_, entry)| entry.transaction.fee)
+    }
+}
+
+/// Valid transactions waiting to be included in a block
+#[derive(Clone)]
+pub struct Mempool {
+    inner: Arc<Mutex<Pool>>,
+}
+
+impl Mempool {
+    pub fn new(limits: Limits) -> Mempool {
+        Mempool {
+            inner: Arc::new(Mutex::new(Pool {
+                limits,
+                entries: HashMap::new(),
+                by_sender: HashMap::new(),
+            })),
+        }
+    }
+
+    /// Validates the transaction against the ledger state of its sender
+    /// and the transactions of the sender which are already pending,
+    /// returns the id of the accepted transaction
+    pub fn insert(
+        &self,
+        transaction: Transaction,
+        account: Account,
+        now: u64,
+    ) -> Result<[u8; 32], MempoolError> {
+        transaction.verify().map_err(MempoolError::Invalid)?;
+        let sender: [u8; 32] = transaction.sender.as_slice().try_into().unwrap();
+        let id = transaction.id();
+
+        let mut pool = self.inner.lock().unwrap();
+        pool.expire(now);
+
+        if pool.entries.contains_key(&id) {
+            return Err(MempoolError::AlreadyKnown);
+        }
+        if transaction.fee < pool.limits.min_fee {
+            return Err(MempoolError::FeeTooLow {
+                min: pool.limits.min_fee,
+            });
+        }
+
+        let pending: Vec<&Entry> = pool
+            .by_sender
+            .get(&sender)
+            .into_iter()
+            .flat_map(|p| p.values())
+            .filter_map(|id| pool.entries.get(id))
+            .collect();
+
+        // nonces have to follow each other, starting at the ledger's one
+        let expected = account.nonce + pending.len() as u64;
+        if transaction.nonce != expected {
+            return Err(MempoolError::BadNonce {
+                expected,
+                got: transaction.nonce,
+            });
+        }
+        if pending.len() >= pool.limits.max_per_sender {
+            return Err(MempoolError::TooManyFromSender);
+        }
+
+        let spent = pending
+            .iter()
+            .map(|e| &e.transaction)
+            .chain(std::iter::once(&transaction))
+            .try_fold(0u64, |sum, tx| {
+                sum.checked_add(tx.amount)?.checked_add(tx.fee)
+            });
+        if spent.is_none_or(|spent| spent > account.balance) {
+            return Err(MempoolError::InsufficientFunds);
+        }
+
+        // a full pool makes room only for a better paying transaction
+        if pool.entries.len() >= pool.limits.max_size {
+            let (victim, nonce) = match pool.eviction_candidate(&sender) {
+                Some((victim, entry)) if entry.transaction.fee < transaction.fee => {
+                    (*victim, entry.transaction.nonce)
+                }
+                _ => return Err(MempoolError::Full),
+            };
+            pool.remove_from(&victim, nonce);
+        }
+
+        pool.by_sender
+            .entry(sender)
+            .or_default()
+            .insert(transaction.nonce, id);
+        pool.entries.insert(
+            id,
+            Entry {
+                transaction,
+                sender,
+                added: now,
+            },
+        );
+
+        Ok(id)
+    }
+
+    /// Transactions for a block, the best paying ones first while the
+    /// transactions of every sender stay in nonce order
+    pub fn select(&self, max: usize) -> Vec<Transaction> {
+        let pool = self.inner.lock().unwrap();
+        let queues: Vec<Vec<&Transaction>> = pool
+            .by_sender
+            .values()
+            .map(|pending| {
+                pending
+                    .values()
+                    .filter_map(|id| pool.entries.get(id))
+                    .map(|e| &e.transaction)
+                    .collect()
+            })
+            .collect();
+
+        // next transaction of every sender, by fee
+        let mut next = vec![0; queues.len()];
+        let mut heads: BinaryHeap<(u64, usize)> = queues
+            .iter()
+            .enumerate()
+            .filter_map(|(i, q)| Some((q.first()?.fee, i)))
+            .collect();
+
+        let mut selected = Vec::new();
+        while selected.len() < max {
+            let Some((_, i)) = heads.pop() else {
+                break;
+            };
+            selected.push(queues[i][next[i]].clone());
+            next[i] += 1;
+            if let Some(tx) = queues[i].get(next[i]) {
+                heads.push((tx.fee, i));
+            }
+        }
+
+        selected
+    }
+
+    /// Drops the transaction once it is in a block, along with the
+    /// earlier ones of its sender which can't be applied anymore
+    pub fn remove_applied(&self, transaction: &Transaction) {
+        let Ok(sender) = <[u8; 32]>::try_from(transaction.sender.as_slice()) else {
+            return;
+        };
+        let mut pool = self.inner.lock().unwrap();
+        let Some(pending) = pool.by_sender.get_mut(&sender) else {
+            return;
+        };
+
+        let later = pending.split_off(&(transaction.nonce + 1));
+        let applied = std::mem::replace(pending, later);
+        if pending.is_empty() {
+            pool.by_sender.remove(&sender);
+        }
+        for id in applied.values() {
+            pool.entries.remove(id);
+        }
+    }
+
+    /// Takes back transactions of reverted blocks. The pending transactions
+    /// of their senders follow them, so everything is inserted again in
+    /// nonce order against the new state of the accounts.
+    pub fn restore(
+        &self,
+        transactions: Vec<Transaction>,
+        account: impl Fn(&[u8; 32]) -> Account,
+        now: u64,
+    ) {
+        let mut restored = transactions;
+        {
+            let mut pool = self.inner.lock().unwrap();
+            let senders: HashSet<[u8; 32]> = restored
+                .iter()
+                .filter_map(|tx| tx.sender.as_slice().try_into().ok())
+                .collect();
+            for sender in senders {
+                let Some(pending) = pool.by_sender.remove(&sender) else {
+                    continue;
+                };
+                for id in pending.values() {
+                    if let Some(entry) = pool.entries.remove(id) {
+                        restored.push(entry.transaction);
+                    }
+                }
+            }
+        }
+
+        restored.sort_by_key(|tx| tx.nonce);
+        for tx in restored {
+            let Ok(sender) = <[u8; 32]>::try_from(tx.sender.as_slice()) else {
+                continue;
+            };
+            // transactions the new branch made invalid are dropped
+            let _ = self.insert(tx, account(&sender), now);
+        }
+    }
+
+    #[cfg(test)]
+    pub fn get(&self, id: &[u8; 32]) -> Option<Transaction> {
+        let pool = self.inner.lock().unwrap();
+        pool.entries.get(id).map(|e| e.transaction.clone())
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().entries.len()
+    }
+}
+
+#[cfg(test)]
+mod mempool_tests {
+    use super::*;
+    use ed25519_dalek::SigningKey;
+    use rand_core::OsRng;
+
+    fn limits() -> Limits {
+        Limits {
+            max_size: 3,
+            max_per_sender: 2,
+            min_fee: 1,
+            expiry: 60,
+        }
+    }
+
+    fn funded() -> Account {
+        Account {
+            balance: 1000,
+            nonce: 0,
+        }
+    }
+
+    #[test]
+    fn validation_test() {
+        let pool = Mempool::new(limits());
+        let key = SigningKey::generate(&mut OsRng);
+
+        let tx = Transaction::new_signed(&key, [2; 32], 10, 1, 0);
+        let id = pool.insert(tx.clone(), funded(), 0).unwrap();
+        assert_eq!(pool.get(&id), Some(tx.clone()));
+        assert!(matches!(
+            pool.insert(tx, funded(), 0),
+            Err(MempoolError::AlreadyKnown)
+        ));
+
+        // the next nonce has to follow the pending transaction
+        let gap = Transaction::new_signed(&key, [2; 32], 10, 1, 2);
+        assert!(matches!(
+            pool.insert(gap, funded(), 0),
+            Err(MempoolError::BadNonce {
+                expected: 1,
+                got: 2
+            })
+        ));
+
+        // pending transactions count against the balance
+        let overspend = Transaction::new_signed(&key, [2; 32], 989, 1, 1);
+        assert!(matches!(
+            pool.insert(overspend, funded(), 0),
+            Err(MempoolError::InsufficientFunds)
+        ));
+
+        let free = Transaction::new_signed(&key, [2; 32], 10, 0, 1);
+        assert!(matches!(
+            pool.insert(free, funded(), 0),
+            Err(MempoolError::FeeTooLow { min: 1 })
+        ));
+
+        let mut forged = Transaction::new_signed(&key, [2; 32], 10, 1, 1);
+        forged.amount = 20;
+        assert!(matches!(
+            pool.insert(forged, funded(), 0),
+            Err(MempoolError::Invalid(_))
+        ));
+
+        pool.insert(
+            Transaction::new_signed(&key, [2; 32], 10, 1, 1),
+            funded(),
+            0,
+        )
+        .unwrap();
+        assert!(matches!(
+            pool.insert(
+                Transaction::new_signed(&key, [2; 32], 10, 1, 2),
+                funded(),
+                0
+            ),
+            Err(MempoolError::TooManyFromSender)
+        ));
+    }
+
+    #[test]
+    fn eviction_test() {
+        let pool = Mempool::new(limits());
+        let keys: Vec<SigningKey> = (0..4).map(|_| SigningKey::generate(&mut OsRng)).collect();
+
+        let cheap = pool
+            .insert(
+                Transaction::new_signed(&keys[0], [2; 32], 1, 1, 0),
+                funded(),
+                0,
+            )
+            .unwrap();
+        for key in &keys[1..3] {
+            pool.insert(Transaction::new_signed(key, [2; 32], 1, 5, 0), funded(), 0)
+                .unwrap();
+        }
+
+        // full, a transaction paying no more than the cheapest one is refused
+        assert!(matches!(
+            pool.insert(
+                Transaction::new_signed(&keys[3], [2; 32], 1, 1, 0),
+                funded(),
+                0
+            ),
+            Err(MempoolError::Full)
+        ));
+        pool.insert(
+            Transaction::new_signed(&keys[3], [2; 32], 1, 2, 0),
+            funded(),
+            0,
+        )
+        .unwrap();
+        assert_eq!(pool.len(), 3);
+        assert!(pool.get(&cheap).is_none());
+    }
+
+    #[test]
+    fn remove_applied_test() {
+        let pool = Mempool::new(limits());
+        let key = SigningKey::generate(&mut OsRng);
+
+        let first = Transaction::new_signed(&key, [2; 32], 1, 1, 0);
+        pool.insert(first.clone(), funded(), 0).unwrap();
+        let second = pool
+            .insert(Transaction::new_signed(&key, [2; 32], 1, 1, 1), funded(), 0)
+            .unwrap();
+
+        pool.remove_applied(&first);
+        assert_eq!(pool.len(), 1);
+        assert!(pool.get(&second).is_some());
+
+        // the following nonce is expected once the first one is applied
+        let applied = Account {
+            balance: 1000,
+            nonce: 1,
+        };
+        assert!(pool
+            .insert(Transaction::new_signed(&key, [2; 32], 1, 1, 2), applied, 0)
+            .is_ok());
+    }
+
+    #[test]
+    fn select_test() {
+        let pool = Mempool::new(Limits {
+            max_size: 10,
+            ..limits()
+        });
+        let cheap = SigningKey::generate(&mut OsRng);
+        let rich = SigningKey::generate(&mut OsRng);
+
+        let first = Transaction::new_signed(&cheap, [2; 32], 1, 1, 0);
+        let second = Transaction::new_signed(&cheap, [2; 32], 1, 9, 1);
+        let other = Transaction::new_signed(&rich, [2; 32], 1, 5, 0);
+        for tx in [&first, &second] {
+            pool.insert(tx.clone(), funded(), 0).unwrap();
+        }
+        pool.insert(other.clone(), funded(), 0).unwrap();
+
+        // the well paying second transaction waits for the first one
+        assert_eq!(pool.select(10), vec![other.clone(), first.clone(), second]);
+        assert_eq!(pool.select(1), vec![other]);
+    }
+
+    #[test]
+    fn restore_test() {
+        let pool = Mempool::new(Limits {
+            max_size: 10,
+            max_per_sender: 10,
+            ..limits()
+        });
+        let key = SigningKey::generate(&mut OsRng);
+        let reverted = Transaction::new_signed(&key, [2; 32], 1, 1, 0);
+        let applied = Account {
+            balance: 998,
+            nonce: 1,
+        };
+        let pending = pool
+            .insert(Transaction::new_signed(&key, [2; 32], 1, 1, 1), applied, 0)
+            .unwrap();
+
+        // the reverted transaction goes in before the pending one
+        pool.restore(vec![reverted.clone()], |_| funded(), 0);
+        assert_eq!(pool.len(), 2);
+        assert!(pool.get(&reverted.id()).is_some());
+        assert!(pool.get(&pending).is_some());
+
+        // a transaction the new state can't pay for is dropped
+        let pool = Mempool::new(limits());
+        pool.restore(vec![reverted], |_| Account::default(), 0);
+        assert_eq!(pool.len(), 0);
+    }
+
+    #[test]
+    fn expiry_test() {
+        let pool = Mempool::new(limits());
+        let key = SigningKey::generate(&mut OsRng);
+
+        let first = pool
+            .insert(Transaction::new_signed(&key, [2; 32], 1, 1, 0), funded(), 0)
+            .unwrap();
+        let second = pool
+            .insert(
+                Transaction::new_signed(&key, [2; 32], 1, 1, 1),
+                funded(),
+                30,
+            )
+            .unwrap();
+
+        // the second transaction can't be applied without the first one
+        let other = SigningKey::generate(&mut OsRng);
+        pool.insert(
+            Transaction::new_signed(&other, [2; 32], 1, 1, 0),
+            funded(),
+            60,
+        )
+        .unwrap();
+        assert!(pool.get(&first).is_none());
+        assert!(pool.get(&second).is_none());
+        assert_eq!(pool.len(), 1);
+    }
+}
@@ -1,15 +1,34 @@
 use crate::errors::*;
+use rand::seq::IteratorRandom;
 use serde::{Deserialize, Serialize};
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 
 pub mod packet_models {
     use super::*;
 
+    /// Version of the wire protocol spoken by this node
+    pub const PROTOCOL_VERSION: u32 = 1;
+
+    /// Oldest protocol version this node can still talk to
+    pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
     #[repr(u8)]
     #[derive(Clone, Copy, PartialEq, Eq, Debug, Deserialize, Serialize)]
     pub enum ErrorCode {
         ParseError = 1,
         BadAddress,
+        IncompatibleVersion,
+        WrongNetwork,
+        BadHello,
+        RateLimited,
+        /// the requested item doesn't exist
+        NotFound,
+        /// the node failed to handle a valid request
+        Internal,
+        /// the transaction has a bad signature, nonce, fee or balance
+        InvalidTransaction,
+        /// the mempool has no room for the transaction
+        MempoolFull,
     }
 
     #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
@@ -23,6 +42,26 @@ pub mod packet_models {
 
         #[allow(non_camel_case_types)]
         error(ErrorR),
+
+        #[allow(non_camel_case_types)]
+        hello(Hello),
+    }
+
+    /// First packet sent by both sides of every session
+    #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+    pub struct Hello {
+        pub protocol_version: u32,
+        pub software_version: String,
+        pub features: u64,
+        pub addr: Vec<u8>,
+        pub identity: Vec<u8>,
+        pub network_id: String,
+        /// random number of the sending process, to detect self-connections
+        #[serde(default)]
+        pub nonce: u64,
+        /// address the sender sees the receiver at
+        #[serde(default)]
+        pub observed: Option<Vec<u8>>,
     }
 
     #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
@@ -39,24 +78,114 @@ pub mod packet_models {
 
         #[allow(non_camel_case_types)]
         announce(AnnounceRequest),
+
+        #[allow(non_camel_case_types)]
+        ping(PingRequest),
+
+        #[allow(non_camel_case_types)]
+        submit_transaction(SubmitTransactionRequest),
+
+        #[allow(non_camel_case_types)]
+        announce_transaction(AnnounceTransactionRequest),
+
+        #[allow(non_camel_case_types)]
+        get_headers(GetHeadersRequest),
+
+        #[allow(non_camel_case_types)]
+        get_blocks(GetBlocksRequest),
+
+        #[allow(non_camel_case_types)]
+        get_transactions(GetTransactionsRequest),
+    }
+
+    impl Request {
+        pub fn set_id(&mut self, id: u64) {
+            match self {
+                Request::get_nodes(r) => r.id = id,
+                Request::get_amount(r) => r.id = id,
+                Request::get_transaction(r) => r.id = id,
+                Request::announce(r) => r.id = id,
+                Request::ping(r) => r.id = id,
+                Request::submit_transaction(r) => r.id = id,
+                Request::announce_transaction(r) => r.id = id,
+                Request::get_headers(r) => r.id = id,
+                Request::get_blocks(r) => r.id = id,
+                Request::get_transactions(r) => r.id = id,
+            }
+        }
+
+        pub fn id(&self) -> u64 {
+            match self {
+                Request::get_nodes(r) => r.id,
+                Request::get_amount(r) => r.id,
+                Request::get_transaction(r) => r.id,
+                Request::announce(r) => r.id,
+                Request::ping(r) => r.id,
+                Request::submit_transaction(r) => r.id,
+                Request::announce_transaction(r) => r.id,
+                Request::get_headers(r) => r.id,
+                Request::get_blocks(r) => r.id,
+                Request::get_transactions(r) => r.id,
+            }
+        }
     }
 
     #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
     #[allow(non_camel_case_types)]
     pub struct GetNodesRequest {
         pub id: u64,
+        /// upper bound for the amount of returned nodes, the responder
+        /// applies its own cap as well
+        #[serde(default)]
+        pub max: Option<u32>,
+        /// only nodes of this family are returned
+        #[serde(default)]
+        pub family: Option<AddressFamily>,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+    pub enum AddressFamily {
+        #[allow(non_camel_case_types)]
+        ipv4,
+
+        #[allow(non_camel_case_types)]
+        ipv6,
+    }
+
+    impl AddressFamily {
+        pub fn matches(&self, addr: &SocketAddr) -> bool {
+            match self {
+                AddressFamily::ipv4 => addr.is_ipv4(),
+                AddressFamily::ipv6 => addr.is_ipv6(),
+            }
+        }
     }
 
     #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
     #[allow(non_camel_case_types)]
     pub struct GetAmountRequest {
         pub id: u64,
+        /// public key of the account
+        #[serde(default)]
+        pub address: Vec<u8>,
     }
 
     #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
     #[allow(non_camel_case_types)]
     pub struct GetTransactionRequest {
         pub id: u64,
+        /// id of the transaction
+        #[serde(default)]
+        pub tx_id: Vec<u8>,
+    }
+
+    #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+    #[allow(non_camel_case_types)]
+    pub struct GetTransactionsRequest {
+        pub id: u64,
+        /// public key of the account
+        #[serde(default)]
+        pub address: Vec<u8>,
     }
 
     #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
@@ -64,6 +193,56 @@ pub mod packet_models {
     pub struct AnnounceRequest {
         pub id: u64,
         pub addr: Vec<u8>,
+        /// static key of the node listening on the address
+        #[serde(default)]
+        pub identity: Option<Vec<u8>>,
+    }
+
+    /// Keepalive, answered with a pong carrying the same nonce
+    #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+    #[allow(non_camel_case_types)]
+    pub struct PingRequest {
+        pub id: u64,
+        pub nonce: u64,
+    }
+
+    /// Transaction from a client, answered with its id once it is
+    /// in the mempool
+    #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+    #[allow(non_camel_case_types)]
+    pub struct SubmitTransactionRequest {
+        pub id: u64,
+        pub transaction: transaction_models::Transaction,
+    }
+
+    /// Transaction gossiped between nodes, not answered unless rejected
+    #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+    #[allow(non_camel_case_types)]
+    pub struct AnnounceTransactionRequest {
+        pub id: u64,
+        pub transaction: transaction_models::Transaction,
+    }
+
+    /// Headers of the main chain following the first block of the locator
+    /// the responder knows
+    #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+    #[allow(non_camel_case_types)]
+    pub struct GetHeadersRequest {
+        pub id: u64,
+        /// ids of blocks of the requester's chain, newest first
+        pub locator: Vec<Vec<u8>>,
+        /// upper bound for the amount of returned headers, the responder
+        /// applies its own cap as well
+        #[serde(default)]
+        pub max: Option<u32>,
+    }
+
+    #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+    #[allow(non_camel_case_types)]
+    pub struct GetBlocksRequest {
+        pub id: u64,
+        /// ids of the blocks, unknown ones are left out of the response
+        pub ids: Vec<Vec<u8>>,
     }
 
     #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
@@ -77,11 +256,44 @@ pub mod packet_models {
 
         #[allow(non_camel_case_types)]
         get_transaction(GetTransactionResponse),
+
+        #[allow(non_camel_case_types)]
+        pong(PongResponse),
+
+        #[allow(non_camel_case_types)]
+        submit_transaction(SubmitTransactionResponse),
+
+        #[allow(non_camel_case_types)]
+        get_headers(GetHeadersResponse),
+
+        #[allow(non_camel_case_types)]
+        get_blocks(GetBlocksResponse),
+
+        #[allow(non_camel_case_types)]
+        get_transactions(GetTransactionsResponse),
+    }
+
+    impl Response {
+        pub fn id(&self) -> u64 {
+            match self {
+                Response::get_nodes(r) => r.id,
+                Response::get_amount(r) => r.id,
+                Response::get_transaction(r) => r.id,
+                Response::pong(r) => r.id,
+                Response::submit_transaction(r) => r.id,
+                Response::get_headers(r) => r.id,
+                Response::get_blocks(r) => r.id,
+                Response::get_transactions(r) => r.id,
+            }
+        }
     }
 
     #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
     pub struct ErrorR {
         pub code: ErrorCode,
+        /// id of the request this error answers, if any
+        #[serde(default)]
+        pub id: Option<u64>,
     }
 
     #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
@@ -94,13 +306,73 @@ pub mod packet_models {
     #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
     pub struct GetAmountReponse {
         pub id: u64,
-        pub amount: Option<Vec<u8>>,
+        pub amount: u64,
+        /// nonce the next transaction of the account has to use
+        pub nonce: u64,
+    }
+
+    #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+    pub struct GetTransactionsResponse {
+        pub id: u64,
+        /// ids of applied transactions the account sent or received
+        pub tx_ids: Vec<Vec<u8>>,
     }
 
     #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
     pub struct GetTransactionResponse {
         pub id: u64,
-        pub transaction: Vec<u8>,
+        pub transaction: transaction_models::Transaction,
+        /// id of the main chain block including the transaction
+        #[serde(default)]
+        pub block: Vec<u8>,
+        /// position of the transaction in the block
+        #[serde(default)]
+        pub index: u64,
+        /// Merkle proof of the transaction against the root in the header
+        /// of the block
+        #[serde(default)]
+        pub proof: Vec<Vec<u8>>,
+    }
+
+    impl GetTransactionResponse {
+        /// Checks that the transaction is signed and included in the block
+        /// with the header, which a light client takes from the chain of
+        /// headers it trusts
+        pub fn verify(&self, header: &block_models::Header) -> bool {
+            header.id().as_slice() == self.block
+                && self.transaction.verify().is_ok()
+                && block_models::verify_merkle_proof(
+                    &self.transaction.id(),
+                    self.index,
+                    &self.proof,
+                    &header.transactions_root,
+                )
+        }
+    }
+
+    #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+    pub struct PongResponse {
+        pub id: u64,
+        pub nonce: u64,
+    }
+
+    #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+    pub struct SubmitTransactionResponse {
+        pub id: u64,
+        pub tx_id: Vec<u8>,
+    }
+
+    #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+    pub struct GetHeadersResponse {
+        pub id: u64,
+        /// consecutive headers, oldest first
+        pub headers: Vec<block_models::Header>,
+    }
+
+    #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+    pub struct GetBlocksResponse {
+        pub id: u64,
+        pub blocks: Vec<block_models::Block>,
     }
 
     #[cfg(test)]
@@ -115,6 +387,7 @@ pub mod packet_models {
 
             let obj = Packet::error(ErrorR {
                 code: ErrorCode::ParseError,
+                id: Some(5),
             });
 
             obj.serialize(&mut Serializer::new(&mut buf)).unwrap();
@@ -129,15 +402,99 @@ pub mod packet_models {
         fn test_request() {
             let mut buf: Vec<u8> = Vec::new();
 
-            let mut addr: Vec<u8> = Vec::with_capacity(6);
-            addr.push(127);
-            addr.push(0);
-            addr.push(0);
-            addr.push(1);
-            addr.push(0);
-            addr.push(255);
+            let addr: Vec<u8> = vec![127, 0, 0, 1, 0, 255];
 
-            let obj = Packet::request(Request::announce(AnnounceRequest { id: 20, addr }));
+            let obj = Packet::request(Request::announce(AnnounceRequest {
+                id: 20,
+                addr,
+                identity: Some(vec![3; 32]),
+            }));
+
+            obj.serialize(&mut Serializer::new(&mut buf)).unwrap();
+
+            let deserialized =
+                Packet::deserialize(&mut Deserializer::new(Cursor::new(buf))).unwrap();
+
+            assert_eq!(obj, deserialized);
+        }
+
+        #[test]
+        fn test_get_nodes() {
+            let mut buf: Vec<u8> = Vec::new();
+
+            let obj = Packet::request(Request::get_nodes(GetNodesRequest {
+                id: 3,
+                max: Some(50),
+                family: Some(AddressFamily::ipv6),
+            }));
+
+            obj.serialize(&mut Serializer::new(&mut buf)).unwrap();
+
+            let deserialized =
+                Packet::deserialize(&mut Deserializer::new(Cursor::new(buf))).unwrap();
+
+            assert_eq!(obj, deserialized);
+        }
+
+        #[test]
+        fn test_ping() {
+            for obj in [
+                Packet::request(Request::ping(PingRequest { id: 4, nonce: 77 })),
+                Packet::response(Response::pong(PongResponse { id: 4, nonce: 77 })),
+            ] {
+                let mut buf: Vec<u8> = Vec::new();
+                obj.serialize(&mut Serializer::new(&mut buf)).unwrap();
+
+                let deserialized =
+                    Packet::deserialize(&mut Deserializer::new(Cursor::new(buf))).unwrap();
+
+                assert_eq!(obj, deserialized);
+            }
+        }
+
+        #[test]
+        fn test_submit_transaction() {
+            let key = ed25519_dalek::SigningKey::from_bytes(&[1; 32]);
+            let transaction = transaction_models::Transaction::new_signed(&key, [2; 32], 10, 1, 0);
+
+            for obj in [
+                Packet::request(Request::submit_transaction(SubmitTransactionRequest {
+                    id: 3,
+                    transaction: transaction.clone(),
+                })),
+                Packet::request(Request::announce_transaction(AnnounceTransactionRequest {
+                    id: 9,
+                    transaction: transaction.clone(),
+                })),
+                Packet::response(Response::submit_transaction(SubmitTransactionResponse {
+                    id: 3,
+                    tx_id: transaction.id().to_vec(),
+                })),
+            ] {
+                let mut buf: Vec<u8> = Vec::new();
+                obj.serialize(&mut Serializer::new(&mut buf)).unwrap();
+
+                let deserialized =
+                    Packet::deserialize(&mut Deserializer::new(Cursor::new(buf))).unwrap();
+
+                assert_eq!(obj, deserialized);
+            }
+        }
+
+        #[test]
+        fn test_hello() {
+            let mut buf: Vec<u8> = Vec::new();
+
+            let obj = Packet::hello(Hello {
+                protocol_version: PROTOCOL_VERSION,
+                software_version: "0.1.0".to_string(),
+                features: 0,
+                addr: vec![127, 0, 0, 1, 0, 255],
+                identity: vec![7; 32],
+                network_id: "testnet".to_string(),
+                nonce: 42,
+                observed: Some(vec![203, 0, 113, 7, 0x13, 0xba]),
+            });
 
             obj.serialize(&mut Serializer::new(&mut buf)).unwrap();
 
@@ -154,8 +511,534 @@ pub mod peers_dump {
 
     #[derive(Debug, Deserialize, Serialize)]
     pub struct Peers {
+        /// bare addresses, written by older versions
         pub ipv4: Option<Vec<u8>>,
         pub ipv6: Option<Vec<u8>>,
+        #[serde(default)]
+        pub entries: Vec<Entry>,
+    }
+
+    /// How the node learned about an address
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+    pub enum AddressSource {
+        /// loaded from a dump without metadata
+        #[allow(non_camel_case_types)]
+        dump,
+
+        #[allow(non_camel_case_types)]
+        announce,
+
+        #[allow(non_camel_case_types)]
+        get_nodes,
+
+        /// connected to without hearing of it first
+        #[allow(non_camel_case_types)]
+        dialed,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+    pub enum Direction {
+        Inbound,
+        Outbound,
+    }
+
+    /// Address book entry with its metadata
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct Entry {
+        pub addr: Vec<u8>,
+        pub source: AddressSource,
+        pub first_seen: u64,
+        pub last_seen: u64,
+        pub last_success: Option<u64>,
+        pub failures: u32,
+        pub identity: Option<Vec<u8>>,
+        pub direction: Option<Direction>,
+        pub tried: bool,
+        /// smoothed round trip time, in milliseconds
+        #[serde(default)]
+        pub rtt: Option<u64>,
+    }
+}
+
+pub mod block_models {
+    use super::*;
+    use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+    use sha2::{Digest, Sha256};
+
+    /// Part of a block which links it into the chain, synced before the
+    /// block itself
+    #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+    pub struct Header {
+        /// id of the previous block, the genesis block has none and commits
+        /// to the initial allocations instead
+        pub parent: Vec<u8>,
+        pub height: u64,
+        /// unix time the block was produced at
+        pub timestamp: u64,
+        /// Merkle root over the ids of the transactions, in block order
+        pub transactions_root: Vec<u8>,
+        /// public key of the node which produced the block
+        #[serde(default)]
+        pub producer: Vec<u8>,
+        /// signature of the producer over the rest of the header
+        #[serde(default)]
+        pub signature: Vec<u8>,
+    }
+
+    impl Header {
+        /// SHA-256 of the MessagePack encoding
+        pub fn id(&self) -> [u8; 32] {
+            Sha256::digest(rmp_serde::to_vec(self).unwrap()).into()
+        }
+
+        fn signing_payload(&self) -> Vec<u8> {
+            let unsigned = Header {
+                signature: Vec::new(),
+                ..self.clone()
+            };
+            rmp_serde::to_vec(&unsigned).unwrap()
+        }
+
+        /// Sets the key as the producer and signs the header with it
+        pub fn sign(&mut self, key: &SigningKey) {
+            self.producer = key.verifying_key().to_bytes().to_vec();
+            self.signature = key.sign(&self.signing_payload()).to_bytes().to_vec();
+        }
+
+        /// Checks that the producer signed the header
+        pub fn verify_signature(&self) -> bool {
+            let Ok(producer) = <[u8; 32]>::try_from(self.producer.as_slice()) else {
+                return false;
+            };
+            let Ok(key) = VerifyingKey::from_bytes(&producer) else {
+                return false;
+            };
+            let Ok(signature) = Signature::from_slice(&self.signature) else {
+                return false;
+            };
+
+            key.verify_strict(&self.signing_payload(), &signature)
+                .is_ok()
+        }
+    }
+
+    #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+    pub struct Block {
+        pub header: Header,
+        pub transactions: Vec<transaction_models::Transaction>,
+    }
+
+    impl Block {
+        pub fn id(&self) -> [u8; 32] {
+            self.header.id()
+        }
+
+        fn leaves(&self) -> Vec<[u8; 32]> {
+            self.transactions.iter().map(|tx| tx.id()).collect()
+        }
+
+        pub fn transactions_root(transactions: &[transaction_models::Transaction]) -> [u8; 32] {
+            let leaves: Vec<_> = transactions.iter().map(|tx| tx.id()).collect();
+            merkle_root(&leaves)
+        }
+
+        /// Merkle proof of the transaction at the index, if there is one
+        pub fn proof(&self, index: usize) -> Option<Vec<[u8; 32]>> {
+            merkle_proof(&self.leaves(), index)
+        }
+    }
+
+    fn hash_leaf(leaf: &[u8; 32]) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update([0]);
+        hasher.update(leaf);
+        hasher.finalize().into()
+    }
+
+    fn hash_node(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update([1]);
+        hasher.update(left);
+        hasher.update(right);
+        hasher.finalize().into()
+    }
+
+    /// Next level of the tree, the last node of an odd level is paired
+    /// with zeros rather than with itself, so that neither a block with a
+    /// repeated last transaction nor a position past the end gets the same
+    /// root
+    fn merkle_level(level: &[[u8; 32]]) -> Vec<[u8; 32]> {
+        level
+            .chunks(2)
+            .map(|pair| hash_node(&pair[0], pair.get(1).unwrap_or(&[0; 32])))
+            .collect()
+    }
+
+    /// Root of the Merkle tree over the leaves. Leaves and inner nodes are
+    /// hashed with different prefixes, so that an inner node can't be
+    /// passed off as a leaf. An empty tree has the hash of nothing as the
+    /// root.
+    pub fn merkle_root(leaves: &[[u8; 32]]) -> [u8; 32] {
+        if leaves.is_empty() {
+            return Sha256::digest([]).into();
+        }
+
+        let mut level: Vec<_> = leaves.iter().map(hash_leaf).collect();
+        while level.len() > 1 {
+            level = merkle_level(&level);
+        }
+        level[0]
+    }
+
+    /// Siblings of the path from the leaf at the index up to the root
+    pub fn merkle_proof(leaves: &[[u8; 32]], mut index: usize) -> Option<Vec<[u8; 32]>> {
+        if index >= leaves.len() {
+            return None;
+        }
+
+        let mut proof = Vec::new();
+        let mut level: Vec<_> = leaves.iter().map(hash_leaf).collect();
+        while level.len() > 1 {
+            proof.push(level.get(index ^ 1).copied().unwrap_or([0; 32]));
+            level = merkle_level(&level);
+            index /= 2;
+        }
+
+        Some(proof)
+    }
+
+    /// Checks that the leaf is at the index of the tree with the root
+    pub fn verify_merkle_proof(
+        leaf: &[u8; 32],
+        mut index: u64,
+        proof: &[Vec<u8>],
+        root: &[u8],
+    ) -> bool {
+        let mut node = hash_leaf(leaf);
+        for sibling in proof {
+            let Ok(sibling) = <[u8; 32]>::try_from(sibling.as_slice()) else {
+                return false;
+            };
+            node = if index.is_multiple_of(2) {
+                hash_node(&node, &sibling)
+            } else {
+                hash_node(&sibling, &node)
+            };
+            index /= 2;
+        }
+
+        index == 0 && node.as_slice() == root
+    }
+
+    #[cfg(test)]
+    mod block_tests {
+        use super::*;
+
+        #[test]
+        fn test_id() {
+            let key = SigningKey::from_bytes(&[1; 32]);
+            let transactions = vec![transaction_models::Transaction::new_signed(
+                &key, [2; 32], 10, 1, 0,
+            )];
+            let block = Block {
+                header: Header {
+                    parent: vec![0; 32],
+                    height: 1,
+                    timestamp: 1000,
+                    transactions_root: Block::transactions_root(&transactions).to_vec(),
+                    producer: Vec::new(),
+                    signature: Vec::new(),
+                },
+                transactions,
+            };
+
+            let mut other = block.header.clone();
+            other.timestamp += 1;
+            assert_ne!(block.id(), other.id());
+
+            let encoded = rmp_serde::to_vec(&block).unwrap();
+            let decoded: Block = rmp_serde::from_slice(&encoded).unwrap();
+            assert_eq!(decoded.id(), block.id());
+            assert_ne!(
+                Block::transactions_root(&decoded.transactions),
+                Block::transactions_root(&[])
+            );
+        }
+
+        #[test]
+        fn test_merkle_proof() {
+            for count in 1..=7u8 {
+                let leaves: Vec<[u8; 32]> = (0..count).map(|i| [i; 32]).collect();
+                let root = merkle_root(&leaves);
+
+                for (index, leaf) in leaves.iter().enumerate() {
+                    let proof: Vec<Vec<u8>> = merkle_proof(&leaves, index)
+                        .unwrap()
+                        .iter()
+                        .map(|h| h.to_vec())
+                        .collect();
+                    assert!(verify_merkle_proof(leaf, index as u64, &proof, &root));
+                    assert!(!verify_merkle_proof(leaf, index as u64 + 1, &proof, &root));
+                    assert!(!verify_merkle_proof(&[9; 32], index as u64, &proof, &root));
+                }
+                assert!(merkle_proof(&leaves, leaves.len()).is_none());
+            }
+
+            // an inner node isn't a leaf
+            let leaves = [[1; 32], [2; 32]];
+            let node = hash_node(&hash_leaf(&leaves[0]), &hash_leaf(&leaves[1]));
+            assert_eq!(merkle_root(&leaves), node);
+            assert_ne!(merkle_root(&[node]), node);
+        }
+
+        #[test]
+        fn test_transaction_response() {
+            let key = SigningKey::from_bytes(&[1; 32]);
+            let transactions: Vec<_> = (0..3)
+                .map(|nonce| {
+                    transaction_models::Transaction::new_signed(&key, [2; 32], 10, 1, nonce)
+                })
+                .collect();
+            let block = Block {
+                header: Header {
+                    parent: vec![0; 32],
+                    height: 1,
+                    timestamp: 1000,
+                    transactions_root: Block::transactions_root(&transactions).to_vec(),
+                    producer: Vec::new(),
+                    signature: Vec::new(),
+                },
+                transactions,
+            };
+
+            let mut response = packet_models::GetTransactionResponse {
+                id: 1,
+                transaction: block.transactions[2].clone(),
+                block: block.id().to_vec(),
+                index: 2,
+                proof: block.proof(2).unwrap().iter().map(|h| h.to_vec()).collect(),
+            };
+            assert!(response.verify(&block.header));
+
+            let mut other = block.header.clone();
+            other.height += 1;
+            assert!(!response.verify(&other));
+
+            response.transaction = block.transactions[1].clone();
+            assert!(!response.verify(&block.header));
+        }
+
+        #[test]
+        fn test_signature() {
+            let key = SigningKey::from_bytes(&[1; 32]);
+            let mut header = Header {
+                parent: vec![0; 32],
+                height: 1,
+                timestamp: 1000,
+                transactions_root: Block::transactions_root(&[]).to_vec(),
+                producer: Vec::new(),
+                signature: Vec::new(),
+            };
+            assert!(!header.verify_signature());
+
+            header.sign(&key);
+            assert!(header.verify_signature());
+
+            header.timestamp += 1;
+            assert!(!header.verify_signature());
+        }
+    }
+}
+
+pub mod transaction_models {
+    use super::*;
+    use ed25519_dalek::{Signature, VerifyingKey};
+    #[cfg(test)]
+    use ed25519_dalek::{Signer, SigningKey};
+    use sha2::{Digest, Sha256};
+
+    /// Transfer of coins between two Ed25519 keys
+    #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+    pub struct Transaction {
+        /// public key of the sender, the signature has to verify with it
+        pub sender: Vec<u8>,
+        /// public key of the recipient
+        pub recipient: Vec<u8>,
+        pub amount: u64,
+        pub fee: u64,
+        /// amount of transactions the sender made before, so that
+        /// a transaction can't be replayed
+        pub nonce: u64,
+        pub signature: Vec<u8>,
+    }
+
+    /// Fields covered by the signature
+    #[derive(Serialize)]
+    struct Unsigned<'a> {
+        sender: &'a [u8],
+        recipient: &'a [u8],
+        amount: u64,
+        fee: u64,
+        nonce: u64,
+    }
+
+    impl Transaction {
+        #[cfg(test)]
+        pub fn new_signed(
+            key: &SigningKey,
+            recipient: [u8; 32],
+            amount: u64,
+            fee: u64,
+            nonce: u64,
+        ) -> Transaction {
+            let mut tx = Transaction {
+                sender: key.verifying_key().to_bytes().to_vec(),
+                recipient: recipient.to_vec(),
+                amount,
+                fee,
+                nonce,
+                signature: Vec::new(),
+            };
+            tx.signature = key.sign(&tx.signing_payload()).to_bytes().to_vec();
+            tx
+        }
+
+        fn signing_payload(&self) -> Vec<u8> {
+            rmp_serde::to_vec(&Unsigned {
+                sender: &self.sender,
+                recipient: &self.recipient,
+                amount: self.amount,
+                fee: self.fee,
+                nonce: self.nonce,
+            })
+            .unwrap()
+        }
+
+        /// Checks that the sender signed the transaction
+        pub fn verify(&self) -> Result<(), models_errors::InvalidTransaction> {
+            let sender: [u8; 32] = self
+                .sender
+                .as_slice()
+                .try_into()
+                .map_err(|_| models_errors::InvalidTransaction::BadKey)?;
+            if self.recipient.len() != 32 {
+                return Err(models_errors::InvalidTransaction::BadKey);
+            }
+
+            let key = VerifyingKey::from_bytes(&sender)
+                .map_err(|_| models_errors::InvalidTransaction::BadKey)?;
+            let signature = Signature::from_slice(&self.signature)
+                .map_err(|_| models_errors::InvalidTransaction::BadSignature)?;
+
+            key.verify_strict(&self.signing_payload(), &signature)
+                .map_err(|_| models_errors::InvalidTransaction::BadSignature)
+        }
+
+        /// Canonical encoding: the fields in declaration order as
+        /// a MessagePack array
+        pub fn encode(&self) -> Vec<u8> {
+            rmp_serde::to_vec(self).unwrap()
+        }
+
+        /// Decodes a transaction, refusing any encoding but the canonical one,
+        /// so that a transaction has exactly one id
+        pub fn decode(bytes: &[u8]) -> Result<Transaction, models_errors::InvalidTransaction> {
+            let tx: Transaction = rmp_serde::from_slice(bytes)
+                .map_err(|_| models_errors::InvalidTransaction::Malformed)?;
+            if tx.encode() != bytes {
+                return Err(models_errors::InvalidTransaction::NonCanonical);
+            }
+
+            Ok(tx)
+        }
+
+        /// SHA-256 of the canonical encoding
+        pub fn id(&self) -> [u8; 32] {
+            Sha256::digest(self.encode()).into()
+        }
+    }
+
+    #[cfg(test)]
+    mod transaction_tests {
+        use super::*;
+        use rand_core::OsRng;
+
+        fn transaction() -> Transaction {
+            Transaction::new_signed(&SigningKey::generate(&mut OsRng), [2; 32], 1000, 10, 0)
+        }
+
+        #[test]
+        fn test_roundtrip() {
+            let tx = transaction();
+            let decoded = Transaction::decode(&tx.encode()).unwrap();
+
+            assert_eq!(tx, decoded);
+            assert_eq!(tx.id(), decoded.id());
+            assert!(decoded.verify().is_ok());
+        }
+
+        #[test]
+        fn test_signature() {
+            let mut tx = transaction();
+            tx.amount += 1;
+            assert!(matches!(
+                tx.verify(),
+                Err(models_errors::InvalidTransaction::BadSignature)
+            ));
+
+            let mut tx = transaction();
+            tx.sender = vec![0; 31];
+            assert!(matches!(
+                tx.verify(),
+                Err(models_errors::InvalidTransaction::BadKey)
+            ));
+        }
+
+        #[test]
+        fn test_non_canonical() {
+            let tx = transaction();
+
+            // the same fields encoded as a map instead of an array
+            let mut buf: Vec<u8> = Vec::new();
+            tx.serialize(&mut rmp_serde::Serializer::new(&mut buf).with_struct_map())
+                .unwrap();
+            assert!(matches!(
+                Transaction::decode(&buf),
+                Err(models_errors::InvalidTransaction::NonCanonical)
+            ));
+
+            let mut trailing = tx.encode();
+            trailing.push(0);
+            assert!(Transaction::decode(&trailing).is_err());
+        }
+
+        #[test]
+        fn test_id() {
+            let tx = transaction();
+            let mut other = tx.clone();
+            other.fee += 1;
+
+            assert_ne!(tx.id(), other.id());
+        }
+    }
+}
+
+pub mod bans_dump {
+    use super::*;
+
+    #[derive(Debug, Default, Deserialize, Serialize)]
+    pub struct Bans {
+        pub entries: Vec<Ban>,
+    }
+
+    #[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
+    pub struct Ban {
+        /// 4 or 16 octets of the banned IP
+        pub ip: Vec<u8>,
+        /// unix time the ban ends at, None for permanent bans
+        pub until: Option<u64>,
+        /// how many times the IP was banned
+        pub count: u32,
     }
 }
 
@@ -241,8 +1124,49 @@ pub fn dump_addresses(addrs: &[SocketAddr]) -> (Option<Vec<u8>>, Option<Vec<u8>>
     (ipv4_to_return, ipv6_to_return)
 }
 
+/// Picks at most `max` random addresses of the given family
+pub fn sample_addresses<'a, I>(
+    addrs: I,
+    max: usize,
+    family: Option<packet_models::AddressFamily>,
+) -> Vec<SocketAddr>
+where
+    I: IntoIterator<Item = &'a SocketAddr>,
+{
+    addrs
+        .into_iter()
+        .filter(|addr| family.is_none_or(|f| f.matches(addr)))
+        .copied()
+        .sample(&mut rand::rng(), max)
+}
+
+/// Addresses which can be reached over the internet, private,
+/// link-local, multicast and documentation ranges are not
+pub fn is_routable(ip: &IpAddr) -> bool {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => {
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_multicast()
+                || ip.is_broadcast()
+                || ip.is_documentation())
+        }
+        IpAddr::V6(ip) => {
+            let documentation = ip.segments()[..2] == [0x2001, 0xdb8];
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_unique_local()
+                || ip.is_unicast_link_local()
+                || ip.is_multicast()
+                || documentation)
+        }
+    }
+}
+
 pub fn parse_ipv4(data: &[u8]) -> ResultSmall<Vec<SocketAddr>> {
-    if data.len() % 6 != 0 {
+    if !data.len().is_multiple_of(6) {
         return Err(models_errors::WrongSizeIPv4.into());
     }
     let mut to_return: Vec<SocketAddr> = Vec::with_capacity(data.len() / 6);
@@ -265,7 +1189,7 @@ pub fn parse_ipv4(data: &[u8]) -> ResultSmall<Vec<SocketAddr>> {
 }
 
 pub fn parse_ipv6(data: &[u8]) -> ResultSmall<Vec<SocketAddr>> {
-    if data.len() % 18 != 0 {
+    if !data.len().is_multiple_of(18) {
         return Err(models_errors::WrongSizeIPv6.into());
     }
     let mut to_return: Vec<SocketAddr> = Vec::with_capacity(data.len() / 18);
@@ -289,14 +1213,12 @@ mod dump_parse_tests {
         let expected_ipv6 =
             b"\xfe\x80\xcd\x00\x00\x00\x0c\xde\x12\x57\x00\x00\x21\x1e\x72\x9c\x00\xff";
         let expected_ipv4 = b"\x7f\x00\x00\x01\x00\xff";
-        let mut input: Vec<SocketAddr> = Vec::with_capacity(2);
-
-        input.push(
+        let input: Vec<SocketAddr> = vec![
             "[FE80:CD00:0000:0CDE:1257:0000:211E:729C]:255"
                 .parse()
                 .unwrap(),
-        );
-        input.push("127.0.0.1:255".parse().unwrap());
+            "127.0.0.1:255".parse().unwrap(),
+        ];
 
         let (ipv4, ipv6) = dump_addresses(&input);
 
@@ -306,11 +1228,52 @@ mod dump_parse_tests {
     }
 
     #[test]
-    fn parse_ipv4_test() {
-        let mut expected: Vec<SocketAddr> = Vec::with_capacity(2);
+    fn sample_addresses_test() {
+        let input: Vec<SocketAddr> = (1..=20)
+            .map(|port| SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), port))
+            .chain(std::iter::once("[fe80::1]:255".parse().unwrap()))
+            .collect();
+
+        let sampled = sample_addresses(&input, 5, None);
+        assert_eq!(sampled.len(), 5);
+        assert!(sampled.iter().all(|addr| input.contains(addr)));
+
+        let sampled = sample_addresses(&input, 5, Some(packet_models::AddressFamily::ipv6));
+        assert_eq!(sampled, vec!["[fe80::1]:255".parse().unwrap()]);
+
+        let sampled = sample_addresses(&input, 100, Some(packet_models::AddressFamily::ipv4));
+        assert_eq!(sampled.len(), 20);
+    }
+
+    #[test]
+    fn is_routable_test() {
+        for ip in ["1.1.1.1", "2606:4700::1111", "::ffff:8.8.8.8"] {
+            assert!(is_routable(&ip.parse().unwrap()), "{}", ip);
+        }
 
-        expected.push("127.0.0.1:255".parse().unwrap());
-        expected.push("127.0.0.1:255".parse().unwrap());
+        for ip in [
+            "10.1.2.3",
+            "192.168.0.1",
+            "169.254.0.1",
+            "224.0.0.1",
+            "192.0.2.1",
+            "203.0.113.5",
+            "fd00::1",
+            "fe80::1",
+            "ff02::1",
+            "2001:db8::1",
+            "::ffff:192.168.0.1",
+        ] {
+            assert!(!is_routable(&ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn parse_ipv4_test() {
+        let expected: Vec<SocketAddr> = vec![
+            "127.0.0.1:255".parse().unwrap(),
+            "127.0.0.1:255".parse().unwrap(),
+        ];
 
         let dump_ipv4 = b"\x7f\x00\x00\x01\x00\xff\x7f\x00\x00\x01\x00\xff";
 
@@ -321,18 +1284,14 @@ mod dump_parse_tests {
 
     #[test]
     fn parse_ipv6_test() {
-        let mut expected: Vec<SocketAddr> = Vec::with_capacity(2);
-
-        expected.push(
+        let expected: Vec<SocketAddr> = vec![
             "[FE80:CD00:0000:0CDE:1257:0000:211E:729C]:255"
                 .parse()
                 .unwrap(),
-        );
-        expected.push(
             "[FE80:CD00:0000:0CDE:1257:0000:211E:729C]:255"
                 .parse()
                 .unwrap(),
-        );
+        ];
 
         let dump_ipv6 = b"\xfe\x80\xcd\x00\x00\x00\x0c\xde\x12\x57\x00\x00\x21\x1e\x72\x9c\x00\xff\xfe\x80\xcd\x00\x00\x00\x0c\xde\x12\x57\x00\x00\x21\x1e\x72\x9c\x00\xff";
 
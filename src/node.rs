@@ -1,54 +1,201 @@
-use std::collections::HashSet;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 
-use chacha20::cipher::StreamCipher;
-use chacha20::cipher::StreamCipherSeek;
-use tokio::sync::broadcast::Receiver;
+use tokio::sync::broadcast::error::RecvError;
 use tokio::sync::broadcast::Sender;
 
+use crate::address_book::AddressBook;
+use crate::bans::{BanList, Offence};
+use crate::chain::{Chain, Inclusion, MAX_BLOCKS, MAX_HEADERS};
 use crate::config::*;
+use crate::connections::{ConnectionManager, DialEvent};
+use crate::errors::chain_errors::SyncError;
+use crate::errors::mempool_errors::MempoolError;
 use crate::errors::*;
+use crate::external::ExternalAddress;
+use crate::gossip::Gossip;
+use crate::ledger::Ledger;
+use crate::mempool::Mempool;
 use crate::models;
 use crate::models::*;
-use chacha20::cipher::KeyIvInit;
-use chacha20::ChaCha20;
+use crate::rate_limit::{Budget, Budgets, RateLimits};
+use crate::registry::{SessionHandle, SessionRegistry};
+use crate::requests::{Command, RequestTracker, Resolution};
+use crate::session;
+use crate::session::{CipherState, Handshake, Role, Session};
+use crate::slots::{SlotGuard, Slots};
+use crate::tools::{current_time, to_hex};
+use ed25519_dalek::SigningKey;
 use lazy_static::lazy_static;
 use rand_core::OsRng;
 use rmp_serde::{Deserializer, Serializer};
 use serde::Deserialize;
 use serde::Serialize;
-use std::fs::File;
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
 use std::io::prelude::*;
 use std::io::Cursor;
+use std::os::unix::fs::OpenOptionsExt;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::time::Duration;
-use x25519_dalek::{EphemeralSecret, PublicKey, SharedSecret};
+use tokio::sync::{mpsc, oneshot, Notify};
+use tokio::time::{Duration, Instant};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+static NEXT_SESSION_ID: AtomicU64 = AtomicU64::new(0);
 
 lazy_static! {
     static ref PEER_TIMEOUT: Duration = Duration::from_secs(15);
+    pub static ref REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+    static ref IDENTITY: StaticSecret =
+        load_identity().expect("Failed to load or create the node identity");
+    /// Sent in the hello, a hello carrying it came from this very process
+    static ref NONCE: u64 = rand::random();
 }
 
 const PEERS_BACKUP_FILE: &str = "peers.dump";
+const BANS_FILE: &str = "bans.dump";
+const IDENTITY_FILE: &str = "node.key";
+
+/// Feature bits advertised in the hello
+const FEATURES: u64 = 0;
+
+const COMMANDS_CAPACITY: usize = 32;
+const REQUEST_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+const DIAL_EVENTS_CAPACITY: usize = 32;
+const MANAGER_TICK: Duration = Duration::from_secs(1);
+const GET_NODES_INTERVAL: Duration = Duration::from_secs(600);
+const PRODUCE_TICK: Duration = Duration::from_secs(1);
+const MAX_GET_NODES_RESPONSE: usize = 1000;
+/// Unanswered pings whose late pongs are still recognized
+const EXPIRED_PINGS: usize = 8;
+const MAX_GET_TRANSACTIONS_RESPONSE: usize = 1000;
+/// Bytes of a frame kept for everything but the blocks of a get_blocks
+/// response: the other fields, zstd and encryption overhead
+const RESPONSE_HEADROOM: usize = 16 * 1024;
+
+/// Handles to the state shared by all tasks of the node
+#[derive(Clone)]
+pub struct Shared {
+    pub shutdown: Sender<u8>,
+    pub book: AddressBook,
+    pub bans: BanList,
+    pub external: ExternalAddress,
+    pub ledger: Ledger,
+    pub mempool: Mempool,
+    pub chain: Chain,
+    pub propagate: Gossip,
+    pub new_peers_tx: Sender<SocketAddr>,
+    pub registry: SessionRegistry,
+    pub slots: Slots,
+}
 
-macro_rules! read_exact {
-    ($sock:expr, $buf:expr, $propagate:expr) => {
-        loop {
-            let _ = tokio::select! {
-                _msg = $propagate.recv() =>{
-                    continue;
-                },
-                res = $sock.read_exact(&mut $buf) => {
-                    res?;
-                    break;
-                }
-            };
+/// Established session with a peer
+struct Connection {
+    /// unique id of the session inside this process
+    id: u64,
+    reader: OwnedReadHalf,
+    writer: OwnedWriteHalf,
+    session: Session,
+    addr: SocketAddr,
+    /// address the peer accepts connections on
+    peer_addr: SocketAddr,
+    /// static key the peer proved to own during the handshake
+    identity: PublicKey,
+    direction: peers_dump::Direction,
+    hello: packet_models::Hello,
+    tracker: RequestTracker,
+    limits: RateLimits,
+    slot: SlotGuard,
+    /// when the last packet from the peer arrived, shared with the
+    /// registry to tell live sessions from dead ones
+    last_received: Arc<Mutex<Instant>>,
+    /// ping waiting for its pong
+    ping: Option<Ping>,
+    /// ids of recent pings which got no pong in time, oldest first
+    expired_pings: VecDeque<u64>,
+}
+
+struct Ping {
+    id: u64,
+    nonce: u64,
+    sent: Instant,
+}
+
+fn load_identity() -> ResultSmall<StaticSecret> {
+    let mut buf = [0u8; 32];
+
+    match File::open(IDENTITY_FILE) {
+        Ok(mut file) => {
+            file.read_exact(&mut buf)?;
+            Ok(StaticSecret::from(buf))
         }
-    };
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let secret = StaticSecret::random_from_rng(OsRng);
+            // the secret key must be readable by the owner only
+            let mut file = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(IDENTITY_FILE)?;
+            file.write_all(secret.as_bytes())?;
+            Ok(secret)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Key blocks are signed with, only validators have one
+pub fn load_validator_key() -> ResultSmall<Option<SigningKey>> {
+    let mut buf = [0u8; 32];
+
+    match File::open(&*VALIDATOR_KEY_FILE) {
+        Ok(mut file) => {
+            file.read_exact(&mut buf)?;
+            Ok(Some(SigningKey::from_bytes(&buf)))
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Fresh request budgets of a connection
+fn rate_limits() -> RateLimits {
+    RateLimits::new(
+        Budgets {
+            requests: Budget {
+                burst: *REQUESTS_BURST,
+                per_minute: *REQUESTS_PER_MINUTE,
+            },
+            announce: Budget {
+                burst: *ANNOUNCE_BURST,
+                per_minute: *ANNOUNCE_PER_MINUTE,
+            },
+            get_nodes: Budget {
+                burst: *GET_NODES_BURST,
+                per_minute: *GET_NODES_PER_MINUTE,
+            },
+            transactions: Budget {
+                burst: *TRANSACTIONS_BURST,
+                per_minute: *TRANSACTIONS_PER_MINUTE,
+            },
+            sync: Budget {
+                burst: *SYNC_BURST,
+                per_minute: *SYNC_PER_MINUTE,
+            },
+        },
+        Instant::now(),
+    )
 }
 
-pub fn load_peers(peers_mut: Arc<Mutex<HashSet<SocketAddr>>>) -> ResultSmall<()> {
+/// Public half of the node's static identity key
+pub fn identity() -> PublicKey {
+    PublicKey::from(&*IDENTITY)
+}
+
+pub fn load_peers(book: AddressBook) -> ResultSmall<()> {
     let file = File::open(PEERS_BACKUP_FILE)?;
 
     let mut decoder = zstd::Decoder::new(file)?;
@@ -59,42 +206,38 @@ pub fn load_peers(peers_mut: Arc<Mutex<HashSet<SocketAddr>>>) -> ResultSmall<()>
 
     let peers = peers_dump::Peers::deserialize(&mut Deserializer::new(Cursor::new(decoded_data)))?;
 
-    let mut peers_storage = peers_mut.lock().unwrap();
+    // entries go first, so their metadata isn't lost to the bare addresses
+    book.load(peers.entries);
+
     if let Some(dump) = peers.ipv4 {
         let parsed = parse_ipv4(&dump)?;
         for addr in parsed {
-            peers_storage.insert(addr);
+            book.add(addr, peers_dump::AddressSource::dump, None);
         }
     }
 
     if let Some(dump) = peers.ipv6 {
         let parsed = parse_ipv6(&dump)?;
         for addr in parsed {
-            peers_storage.insert(addr);
+            book.add(addr, peers_dump::AddressSource::dump, None);
         }
     }
 
     Ok(())
 }
 
-pub fn dump_peers(peers_mut: Arc<Mutex<HashSet<SocketAddr>>>) -> ResultSmall<()> {
+pub fn dump_peers(book: AddressBook) -> ResultSmall<()> {
     let target = File::create(PEERS_BACKUP_FILE)?;
 
     let mut encoder = zstd::Encoder::new(target, 21)?;
 
-    let peers_storage = peers_mut.lock().unwrap();
-
-    let mut peers: Vec<SocketAddr> = Vec::with_capacity(peers_storage.len());
-
-    for peer in peers_storage.iter() {
-        peers.push(*peer);
-    }
-
-    let (ipv4, ipv6) = dump_addresses(&peers);
-
     let mut buf: Vec<u8> = Vec::new();
 
-    let peers = peers_dump::Peers { ipv4, ipv6 };
+    let peers = peers_dump::Peers {
+        ipv4: None,
+        ipv6: None,
+        entries: book.dump(),
+    };
 
     peers.serialize(&mut Serializer::new(&mut buf))?;
 
@@ -105,26 +248,42 @@ pub fn dump_peers(peers_mut: Arc<Mutex<HashSet<SocketAddr>>>) -> ResultSmall<()>
     Ok(())
 }
 
-pub async fn start(
-    peers_mut: Arc<Mutex<HashSet<SocketAddr>>>,
-    shutdown: Sender<u8>,
-    propagate: Sender<packet_models::Packet>,
-    new_peers_tx: Sender<SocketAddr>,
-) -> Result<(), node_errors::NodeError> {
-    let mut rx = shutdown.subscribe();
-
-    tokio::select! {
-        _ = connect_to_peers(
-            peers_mut.clone(),
-            shutdown.clone(),
-            propagate.clone(),
-            new_peers_tx.clone()
-        ) => {},
-        _ = rx.recv() => {
-            return Ok(());
-        }
+/// Restores the bans, no file just means nobody was banned yet
+pub fn load_bans(bans: BanList) -> ResultSmall<()> {
+    let file = match File::open(BANS_FILE) {
+        Ok(f) => f,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
     };
 
+    let mut decoded_data: Vec<u8> = Vec::new();
+    zstd::Decoder::new(file)?.read_to_end(&mut decoded_data)?;
+
+    let dump = bans_dump::Bans::deserialize(&mut Deserializer::new(Cursor::new(decoded_data)))?;
+    bans.load(dump.entries);
+
+    Ok(())
+}
+
+pub fn dump_bans(bans: BanList) -> ResultSmall<()> {
+    let target = File::create(BANS_FILE)?;
+    let mut encoder = zstd::Encoder::new(target, 21)?;
+
+    let mut buf: Vec<u8> = Vec::new();
+    bans_dump::Bans {
+        entries: bans.dump(),
+    }
+    .serialize(&mut Serializer::new(&mut buf))?;
+
+    encoder.write_all(&buf)?;
+    encoder.finish()?;
+
+    Ok(())
+}
+
+pub async fn start(shared: Shared) -> Result<(), node_errors::NodeError> {
+    let mut rx = shared.shutdown.subscribe();
+
     let listener = match TcpListener::bind(*SERVER_ADDRESS).await {
         Ok(s) => s,
         Err(e) => {
@@ -143,20 +302,28 @@ pub async fn start(
                 }
             },
             _ = rx.recv() => {
-                shutdown.send(0).unwrap();
+                shared.shutdown.send(0).unwrap();
                 break;
             }
         };
 
+        if shared.bans.is_banned(&addr.ip(), current_time()) {
+            println!("Refusing connection from {}: banned", addr);
+            continue;
+        }
+
+        // the slot is taken before the handshake, so a flood of connections
+        // from one network costs nothing
+        let Some(slot) = shared
+            .slots
+            .acquire(addr.ip(), peers_dump::Direction::Inbound)
+        else {
+            println!("Refusing connection from {}: no free slots", addr);
+            continue;
+        };
+
         println!("New connection from: {}", addr);
-        tokio::spawn(handle_incoming(
-            sock,
-            addr,
-            shutdown.clone(),
-            propagate.clone(),
-            peers_mut.clone(),
-            new_peers_tx.clone(),
-        ));
+        tokio::spawn(handle_incoming(sock, addr, slot, shared.clone()));
     }
 
     Ok(())
@@ -165,19 +332,12 @@ pub async fn start(
 async fn handle_incoming(
     socket: TcpStream,
     addr: SocketAddr,
-    shutdown: Sender<u8>,
-    propagate: Sender<packet_models::Packet>,
-    peers: Arc<Mutex<HashSet<SocketAddr>>>,
-    new_peers_tx: Sender<SocketAddr>,
+    slot: SlotGuard,
+    shared: Shared,
 ) -> Result<(), node_errors::NodeError> {
-    let mut rx = shutdown.subscribe();
+    let mut rx = shared.shutdown.subscribe();
     tokio::select! {
-        res = handle_incoming_wrapped(
-            socket,
-            addr,
-            propagate,
-            peers,
-            new_peers_tx) => res,
+        res = handle_incoming_wrapped(socket, addr, slot, shared) => res,
         _ = rx.recv() => {
             Ok(())
         }
@@ -187,40 +347,254 @@ async fn handle_incoming(
 async fn handle_incoming_wrapped(
     mut socket: TcpStream,
     addr: SocketAddr,
-    mut propagate: Sender<packet_models::Packet>,
-    peers: Arc<Mutex<HashSet<SocketAddr>>>,
-    mut new_peers_tx: Sender<SocketAddr>,
+    slot: SlotGuard,
+    shared: Shared,
 ) -> Result<(), node_errors::NodeError> {
-    let mut rx_propagate = propagate.subscribe();
+    let (mut session, identity) =
+        match tokio::time::timeout(*PEER_TIMEOUT, exchange_keys(&mut socket)).await {
+            Ok(res) => res?,
+            Err(_) => {
+                return Err(node_errors::NodeError::new(
+                    "Handshake timed out".to_string(),
+                ));
+            }
+        };
 
-    let mut waiting_response: HashSet<u64> = HashSet::with_capacity(20);
+    let hello = match tokio::time::timeout(
+        *PEER_TIMEOUT,
+        exchange_hello(&mut socket, &mut session, &identity),
+    )
+    .await
+    {
+        Ok(Ok(h)) => h,
+        Ok(Err(e)) => {
+            return Err(node_errors::NodeError::new(e.to_string()));
+        }
+        Err(_) => {
+            return Err(node_errors::NodeError::new("Hello timed out".to_string()));
+        }
+    };
 
-    let (nonce, shared) = exchange_keys(&mut socket).await?;
+    // inbound peers connect from an ephemeral port, they are reachable
+    // on the port from their hello
+    let listen_addr = match bin2addr(&hello.addr) {
+        Ok(a) => a,
+        Err(e) => {
+            return Err(node_errors::NodeError::new(e.to_string()));
+        }
+    };
+    observe_external(&hello, &addr, &shared);
+
+    let (reader, writer) = socket.into_split();
+    let conn = Connection {
+        id: NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed),
+        reader,
+        writer,
+        session,
+        addr,
+        peer_addr: SocketAddr::new(addr.ip(), listen_addr.port()),
+        identity,
+        direction: peers_dump::Direction::Inbound,
+        hello,
+        tracker: RequestTracker::new(*REQUEST_TIMEOUT),
+        limits: rate_limits(),
+        slot,
+        last_received: Arc::new(Mutex::new(Instant::now())),
+        ping: None,
+        expired_pings: VecDeque::with_capacity(EXPIRED_PINGS),
+    };
 
-    let mut cipher = ChaCha20::new(shared.as_bytes().into(), &nonce.into());
+    shared.book.mark_success(
+        &conn.peer_addr,
+        conn.identity.to_bytes(),
+        peers_dump::Direction::Inbound,
+    );
+
+    println!(
+        "Session with {} established, identity: {}, version: {}",
+        conn.addr,
+        to_hex(conn.identity.as_bytes()),
+        conn.hello.software_version
+    );
+
+    run_session(conn, shared).await
+}
+
+/// Makes the session reachable through the registry for its lifetime,
+/// sessions duplicating another one with the same peer are closed
+async fn run_session(mut conn: Connection, shared: Shared) -> Result<(), node_errors::NodeError> {
+    let (commands_tx, commands) = mpsc::channel(COMMANDS_CAPACITY);
+    let superseded = Arc::new(Notify::new());
+    let identity = conn.identity.to_bytes();
+    let registered = shared.registry.register(SessionHandle {
+        id: conn.id,
+        addr: conn.peer_addr,
+        identity,
+        direction: conn.direction,
+        nonce: conn.hello.nonce,
+        last_received: conn.last_received.clone(),
+        commands: commands_tx,
+        superseded: superseded.clone(),
+    });
+    if let Err(e) = registered {
+        println!("Closing session with {}: {}", conn.addr, e);
+        return Err(node_errors::NodeError::new(e.to_string()));
+    }
+
+    let res = session_loop(&mut conn, commands, &superseded, &shared).await;
+
+    shared.registry.unregister(&identity, conn.id);
+
+    res
+}
+
+/// Main loop of an established session: handles packets from the peer,
+/// forwards gossip from the other sessions and requests from local tasks
+async fn session_loop(
+    conn: &mut Connection,
+    mut commands: mpsc::Receiver<Command>,
+    superseded: &Notify,
+    shared: &Shared,
+) -> Result<(), node_errors::NodeError> {
+    let mut sweep = tokio::time::interval(REQUEST_SWEEP_INTERVAL);
+    let mut rx_propagate = shared.propagate.subscribe();
 
-    // main loop
     loop {
-        let packet = match receive_packet(&mut socket, &mut cipher, &mut rx_propagate).await {
-            Ok(p) => p,
-            Err(e) => {
-                return Err(node_errors::NodeError::new(e.to_string()));
+        let packet = {
+            // the read future is kept alive while gossip is being forwarded,
+            // so a partially read frame is never lost
+            let (ip, peer_addr) = (conn.addr.ip(), conn.peer_addr);
+            let recv = async {
+                receive_packet(&mut conn.reader, &mut conn.session.recv)
+                    .await
+                    .map_err(|e| {
+                        if let Some(offence) = frame_offence(e.as_ref()) {
+                            penalize(shared, &ip, &peer_addr, offence);
+                        }
+                        node_errors::NodeError::new(e.to_string())
+                    })
+            };
+            tokio::pin!(recv);
+
+            loop {
+                tokio::select! {
+                    res = &mut recv => break res?,
+                    msg = rx_propagate.recv() => {
+                        let propagated = match msg {
+                            Ok(p) => p,
+                            Err(RecvError::Lagged(_)) => continue,
+                            Err(RecvError::Closed) => {
+                                return Err(node_errors::NodeError::new(
+                                    "Gossip channel closed".to_string(),
+                                ));
+                            }
+                        };
+
+                        // don't echo packets back to where they came from
+                        if propagated.origin == conn.id {
+                            continue;
+                        }
+
+                        if let Err(e) = send_packet(
+                            &mut conn.writer,
+                            &mut conn.session.send,
+                            propagated.packet,
+                        )
+                        .await
+                        {
+                            return Err(node_errors::NodeError::new(e.to_string()));
+                        }
+                    },
+                    cmd = commands.recv() => {
+                        // the registry only drops the handle when another
+                        // session with the peer took over
+                        let Some(cmd) = cmd else {
+                            return Err(node_errors::NodeError::new(
+                                "Replaced by another session with the peer".to_string(),
+                            ));
+                        };
+
+                        match cmd {
+                            Command::Request { mut request, responder } => {
+                                let id = conn.tracker.next_id();
+                                request.set_id(id);
+                                conn.tracker.register(id, responder);
+
+                                if let Err(e) = send_packet(
+                                    &mut conn.writer,
+                                    &mut conn.session.send,
+                                    packet_models::Packet::request(request),
+                                )
+                                .await
+                                {
+                                    return Err(node_errors::NodeError::new(e.to_string()));
+                                }
+                            }
+                        }
+                    },
+                    _ = sweep.tick() => {
+                        let now = Instant::now();
+                        conn.tracker.expire(now);
+
+                        // sessions without any traffic for too long are dead
+                        let idle = now.duration_since(*conn.last_received.lock().unwrap());
+                        if idle >= *IDLE_TIMEOUT {
+                            return Err(node_errors::NodeError::new(
+                                "Session timed out".to_string(),
+                            ));
+                        }
+
+                        // a lost pong must not stop the pings for good
+                        let unanswered = |p: &mut Ping| now.duration_since(p.sent) >= *REQUEST_TIMEOUT;
+                        if let Some(ping) = conn.ping.take_if(unanswered) {
+                            if conn.expired_pings.len() == EXPIRED_PINGS {
+                                conn.expired_pings.pop_front();
+                            }
+                            conn.expired_pings.push_back(ping.id);
+                        }
+
+                        // quiet sessions are pinged, which keeps them alive
+                        // on both sides and measures the round trip time
+                        if idle >= *PING_INTERVAL && conn.ping.is_none() {
+                            let ping = Ping {
+                                id: conn.tracker.next_id(),
+                                nonce: rand::random(),
+                                sent: now,
+                            };
+                            let packet = packet_models::Packet::request(
+                                packet_models::Request::ping(packet_models::PingRequest {
+                                    id: ping.id,
+                                    nonce: ping.nonce,
+                                }),
+                            );
+
+                            if let Err(e) =
+                                send_packet(&mut conn.writer, &mut conn.session.send, packet).await
+                            {
+                                return Err(node_errors::NodeError::new(e.to_string()));
+                            }
+                            conn.ping = Some(ping);
+                        }
+                    }
+                    _ = superseded.notified() => {
+                        println!("Closing duplicate session with {}", conn.addr);
+                        return Err(node_errors::NodeError::new(
+                            "Replaced by another session with the peer".to_string(),
+                        ));
+                    }
+                    _ = conn.slot.evicted() => {
+                        return Err(node_errors::NodeError::new(
+                            "Evicted to make room for another peer".to_string(),
+                        ));
+                    }
+                }
             }
         };
 
+        *conn.last_received.lock().unwrap() = Instant::now();
+
         // handle packet
-        if process_packet(
-            &mut socket,
-            packet,
-            &mut waiting_response,
-            &mut cipher,
-            peers.clone(),
-            &mut propagate,
-            &mut new_peers_tx,
-        )
-        .await
-        .is_err()
-        {
+        if process_packet(conn, packet, shared).await.is_err() {
             break;
         }
     }
@@ -230,155 +604,604 @@ async fn handle_incoming_wrapped(
 
 async fn exchange_keys(
     socket: &mut TcpStream,
-) -> Result<([u8; 12], SharedSecret), node_errors::NodeError> {
-    let mut buf = [0; 32];
-    let secret = EphemeralSecret::random_from_rng(OsRng);
-    let public = PublicKey::from(&secret);
+) -> Result<(Session, PublicKey), node_errors::NodeError> {
+    let mut handshake = Handshake::new(Role::Responder, IDENTITY.clone());
 
-    if let Err(e) = socket.write(public.as_bytes()).await {
+    let mut msg1 = [0u8; session::HANDSHAKE_MSG1_SIZE];
+    if let Err(e) = socket.read_exact(&mut msg1).await {
         return Err(node_errors::NodeError::new(e.to_string()));
     };
+    handshake.read_message_1(&msg1);
 
-    if let Err(e) = socket.read_exact(&mut buf).await {
+    let msg2 = match handshake.write_message_2() {
+        Ok(m) => m,
+        Err(e) => {
+            return Err(node_errors::NodeError::new(e.to_string()));
+        }
+    };
+    if let Err(e) = socket.write_all(&msg2).await {
         return Err(node_errors::NodeError::new(e.to_string()));
     };
 
-    let other_public = PublicKey::from(buf);
-    let shared = secret.diffie_hellman(&other_public);
+    let mut msg3 = [0u8; session::HANDSHAKE_MSG3_SIZE];
+    if let Err(e) = socket.read_exact(&mut msg3).await {
+        return Err(node_errors::NodeError::new(e.to_string()));
+    };
+    if let Err(e) = handshake.read_message_3(&msg3) {
+        return Err(node_errors::NodeError::new(e.to_string()));
+    };
 
-    let nonce = [0u8; 12];
+    match handshake.finish() {
+        Ok(r) => Ok(r),
+        Err(e) => Err(node_errors::NodeError::new(e.to_string())),
+    }
+}
 
-    Ok((nonce, shared))
+fn local_hello(observed: &SocketAddr) -> packet_models::Hello {
+    packet_models::Hello {
+        protocol_version: packet_models::PROTOCOL_VERSION,
+        software_version: env!("CARGO_PKG_VERSION").to_string(),
+        features: FEATURES,
+        addr: addr2bin(&SERVER_ADDRESS),
+        identity: identity().as_bytes().to_vec(),
+        network_id: NETWORK_ID.clone(),
+        nonce: *NONCE,
+        observed: Some(addr2bin(observed)),
+    }
 }
 
-async fn receive_packet(
+fn check_hello(
+    hello: &packet_models::Hello,
+    identity: &PublicKey,
+) -> Option<packet_models::ErrorCode> {
+    if hello.protocol_version < packet_models::MIN_PROTOCOL_VERSION {
+        return Some(packet_models::ErrorCode::IncompatibleVersion);
+    }
+
+    if hello.network_id != *NETWORK_ID {
+        return Some(packet_models::ErrorCode::WrongNetwork);
+    }
+
+    // hello has to come from the key which was proven in the handshake
+    if hello.identity != identity.as_bytes() || bin2addr(&hello.addr).is_err() {
+        return Some(packet_models::ErrorCode::BadHello);
+    }
+
+    None
+}
+
+/// Sends our hello and reads the peer's one, which has to be the first
+/// packet of the session, incompatible peers get an error and are refused
+async fn exchange_hello(
     socket: &mut TcpStream,
-    cipher: &mut ChaCha20,
-    propagate: &mut Receiver<packet_models::Packet>,
+    session: &mut Session,
+    identity: &PublicKey,
+) -> ResultSmall<packet_models::Hello> {
+    let observed = socket.peer_addr()?;
+    send_packet(
+        socket,
+        &mut session.send,
+        packet_models::Packet::hello(local_hello(&observed)),
+    )
+    .await?;
+
+    let reason = match receive_packet(socket, &mut session.recv).await? {
+        packet_models::Packet::hello(hello) if hello.nonce == *NONCE => {
+            return Err(node_errors::SelfConnection {}.into());
+        }
+        packet_models::Packet::hello(hello) => match check_hello(&hello, identity) {
+            None => return Ok(hello),
+            Some(reason) => reason,
+        },
+        _ => packet_models::ErrorCode::BadHello,
+    };
+
+    let response_packet = packet_models::Packet::error(packet_models::ErrorR {
+        code: reason,
+        id: None,
+    });
+    send_packet(socket, &mut session.send, response_packet).await?;
+
+    Err(node_errors::IncompatiblePeer { reason }.into())
+}
+
+async fn receive_packet<R: AsyncRead + Unpin>(
+    socket: &mut R,
+    cipher: &mut CipherState,
 ) -> ResultSmall<packet_models::Packet> {
-    // read size of the packet
-    let mut recv_buffer = [0u8; 4];
-    read_exact!(socket, recv_buffer, propagate);
-    let packet_size = u32::from_be_bytes(recv_buffer) as usize;
+    // read size and counter of the packet
+    let mut header = [0u8; session::FRAME_HEADER_SIZE];
+    socket.read_exact(&mut header).await?;
+    let (packet_size, _) = session::parse_header(&header);
+
+    // the size comes from the peer, nothing is allocated before it is checked
+    if packet_size > *MAX_FRAME_SIZE {
+        return Err(node_errors::PacketTooLarge {
+            size: packet_size,
+            max: *MAX_FRAME_SIZE,
+        }
+        .into());
+    }
 
     // read actual packet
     let mut recv_buffer = vec![0u8; packet_size];
-    read_exact!(socket, recv_buffer, propagate);
+    socket.read_exact(&mut recv_buffer).await?;
 
-    // decrypt packet
-    cipher.apply_keystream(&mut recv_buffer);
-    cipher.seek(0);
+    // authenticate and decrypt packet
+    let recv_buffer = cipher.open(&header, &recv_buffer)?;
 
     // uncompress packet
-    let mut decoded_data: Vec<u8> = Vec::with_capacity(packet_size);
-    let cur = Cursor::new(recv_buffer);
-    let mut decoder = zstd::Decoder::new(cur)?;
-    decoder.read_to_end(&mut decoded_data)?;
+    let decoded_data = decompress(&recv_buffer, *MAX_PAYLOAD_SIZE)?;
 
     // deserialize packet
     let packet =
-        packet_models::Packet::deserialize(&mut Deserializer::new(Cursor::new(decoded_data)))?;
+        packet_models::Packet::deserialize(&mut Deserializer::new(Cursor::new(decoded_data)))
+            .map_err(|e| node_errors::MalformedPacket {
+                reason: e.to_string(),
+            })?;
 
     Ok(packet)
 }
 
-async fn send_packet(
-    socket: &mut TcpStream,
-    cipher: &mut ChaCha20,
+/// Decodes zstd data, refusing to produce more than `max` bytes
+fn decompress(data: &[u8], max: usize) -> ResultSmall<Vec<u8>> {
+    let mut decoded_data: Vec<u8> = Vec::with_capacity(data.len().min(max));
+    let malformed = |e: std::io::Error| node_errors::MalformedPacket {
+        reason: e.to_string(),
+    };
+    let decoder = zstd::Decoder::new(Cursor::new(data)).map_err(malformed)?;
+
+    // one byte over the limit is enough to tell that it was exceeded
+    decoder
+        .take(max as u64 + 1)
+        .read_to_end(&mut decoded_data)
+        .map_err(malformed)?;
+    if decoded_data.len() > max {
+        return Err(node_errors::PacketTooLarge {
+            size: decoded_data.len(),
+            max,
+        }
+        .into());
+    }
+
+    Ok(decoded_data)
+}
+
+async fn send_packet<W: AsyncWrite + Unpin>(
+    socket: &mut W,
+    cipher: &mut CipherState,
     packet: packet_models::Packet,
 ) -> ResultSmall<()> {
     let mut buf: Vec<u8> = Vec::with_capacity(100);
 
     packet.serialize(&mut Serializer::new(&mut buf)).unwrap();
+    if buf.len() > *MAX_PAYLOAD_SIZE {
+        return Err(node_errors::PacketTooLarge {
+            size: buf.len(),
+            max: *MAX_PAYLOAD_SIZE,
+        }
+        .into());
+    }
 
-    let mut encoded_data: Vec<u8> = vec![0u8; buf.len()];
-    let cur = Cursor::new(&mut encoded_data);
-    let mut encoder = zstd::Encoder::new(cur, 21)?;
+    let mut encoded_data: Vec<u8> = Vec::with_capacity(buf.len());
+    let mut encoder = zstd::Encoder::new(&mut encoded_data, 21)?;
     encoder.write_all(&buf)?;
     encoder.finish()?;
 
-    cipher.apply_keystream(&mut encoded_data);
-    cipher.seek(0);
+    // the peer would drop the session and blame us for a larger frame,
+    // so it isn't sent at all
+    let frame_size = encoded_data.len() + session::TAG_SIZE;
+    if frame_size > *MAX_FRAME_SIZE {
+        return Err(node_errors::PacketTooLarge {
+            size: frame_size,
+            max: *MAX_FRAME_SIZE,
+        }
+        .into());
+    }
 
-    let packet_size = &(encoded_data.len() as u32).to_be_bytes();
-    socket.write_all(packet_size).await?;
-    socket.write_all(&encoded_data).await?;
+    let frame = cipher.seal(&encoded_data)?;
+    socket.write_all(&frame).await?;
 
     Ok(())
 }
 
-async fn connect_to_peers(
-    peers_mut: Arc<Mutex<HashSet<SocketAddr>>>,
-    shutdown: Sender<u8>,
-    propagate: Sender<packet_models::Packet>,
-    new_peers_tx: Sender<SocketAddr>,
-) {
-    let peers = peers_mut.lock().unwrap();
-
-    for peer in peers.iter() {
-        tokio::spawn(connect_to_peer(
-            *peer,
-            peers_mut.clone(),
-            shutdown.clone(),
-            propagate.clone(),
-            new_peers_tx.clone(),
-        ));
-    }
-}
-
 async fn exchange_keys_client(
     socket: &mut TcpStream,
-) -> Result<([u8; 12], SharedSecret), node_errors::NodeError> {
-    let mut buf = [0; 32];
-    let secret = EphemeralSecret::random_from_rng(OsRng);
-    let public = PublicKey::from(&secret);
-    if let Err(e) = socket.read_exact(&mut buf).await {
+) -> Result<(Session, PublicKey), node_errors::NodeError> {
+    let mut handshake = Handshake::new(Role::Initiator, IDENTITY.clone());
+
+    if let Err(e) = socket.write_all(&handshake.write_message_1()).await {
         return Err(node_errors::NodeError::new(e.to_string()));
     };
 
-    if let Err(e) = socket.write(public.as_bytes()).await {
+    let mut msg2 = [0u8; session::HANDSHAKE_MSG2_SIZE];
+    if let Err(e) = socket.read_exact(&mut msg2).await {
+        return Err(node_errors::NodeError::new(e.to_string()));
+    };
+    if let Err(e) = handshake.read_message_2(&msg2) {
         return Err(node_errors::NodeError::new(e.to_string()));
     };
 
-    let other_public = PublicKey::from(buf);
-    let shared = secret.diffie_hellman(&other_public);
-
-    let nonce = [0u8; 12];
+    let msg3 = match handshake.write_message_3() {
+        Ok(m) => m,
+        Err(e) => {
+            return Err(node_errors::NodeError::new(e.to_string()));
+        }
+    };
+    if let Err(e) = socket.write_all(&msg3).await {
+        return Err(node_errors::NodeError::new(e.to_string()));
+    };
 
-    Ok((nonce, shared))
+    match handshake.finish() {
+        Ok(r) => Ok(r),
+        Err(e) => Err(node_errors::NodeError::new(e.to_string())),
+    }
 }
 
-pub async fn connect_to_peer(
+/// Dials the address and runs the session, reporting its progress
+/// to the connection manager
+async fn connect_to_peer(
     addr: SocketAddr,
-    peers_mut: Arc<Mutex<HashSet<SocketAddr>>>,
-    shutdown: Sender<u8>,
-    propagate: Sender<packet_models::Packet>,
-    new_peers_tx: Sender<SocketAddr>,
+    shared: Shared,
+    events: mpsc::Sender<(SocketAddr, DialEvent)>,
 ) {
-    let mut rx = shutdown.subscribe();
+    let Some(slot) = shared
+        .slots
+        .acquire(addr.ip(), peers_dump::Direction::Outbound)
+    else {
+        let _ = events.send((addr, DialEvent::Refused)).await;
+        return;
+    };
+
+    let mut rx = shared.shutdown.subscribe();
+    let res = tokio::select! {
+        _ = rx.recv() => return,
+        res = establish_outbound(&addr, slot, &shared) => res,
+    };
+
+    let conn = match res {
+        Ok(c) => c,
+        Err(_) => {
+            shared.book.mark_failure(&addr);
+            let _ = events.send((addr, DialEvent::Failed)).await;
+            return;
+        }
+    };
+    shared.book.mark_success(
+        &addr,
+        conn.identity.to_bytes(),
+        peers_dump::Direction::Outbound,
+    );
+    let _ = events.send((addr, DialEvent::Established)).await;
+
     tokio::select! {
         _ = rx.recv() => {},
-        _ = handle_peer(
-            &addr,
-            peers_mut.clone(),
-            propagate,
-            new_peers_tx
-        ) => {}
+        _ = handle_peer(conn, shared.clone()) => {}
     };
 
-    let mut peers = peers_mut.lock().unwrap();
-    peers.remove(&addr);
+    // the manager is gone while shutting down
+    let _ = events.send((addr, DialEvent::Disconnected)).await;
 }
 
-pub async fn handle_peer(
-    addr: &SocketAddr,
-    peers_mut: Arc<Mutex<HashSet<SocketAddr>>>,
-    mut propagate: Sender<packet_models::Packet>,
-    mut new_peers_tx: Sender<SocketAddr>,
-) -> Result<(), node_errors::NodeError> {
-    // set up
-    let mut rx_propagate = propagate.subscribe();
+async fn handle_peer(mut conn: Connection, shared: Shared) -> Result<(), node_errors::NodeError> {
+    println!(
+        "Session with {} established, identity: {}, version: {}",
+        conn.addr,
+        to_hex(conn.identity.as_bytes()),
+        conn.hello.software_version
+    );
+
+    // announce, unless other nodes couldn't reach us at the address,
+    // our own announce must not be forwarded back to us
+    let announced = shared.external.announced();
+    if is_acceptable_peer_address(&announced) {
+        let id: u64 = rand::random();
+        shared.propagate.mark_seen(id);
+
+        let packet = packet_models::Packet::request(packet_models::Request::announce(
+            packet_models::AnnounceRequest {
+                id,
+                addr: models::addr2bin(&announced),
+                identity: Some(identity().as_bytes().to_vec()),
+            },
+        ));
+
+        if let Err(e) = send_packet(&mut conn.writer, &mut conn.session.send, packet).await {
+            return Err(node_errors::NodeError::new(e.to_string()));
+        };
+    }
+
+    // the session has to be registered before discovery sends requests to it
+    let addr = conn.peer_addr;
+    tokio::select! {
+        biased;
+        res = run_session(conn, shared.clone()) => res,
+        _ = discover_peers(addr, &shared) => Ok(()),
+        _ = sync_chain(addr, &shared) => Ok(()),
+    }
+}
+
+/// Produces blocks whenever the consensus gives this node a turn
+pub async fn produce_blocks(shared: Shared) {
+    let mut shutdown_watcher = shared.shutdown.subscribe();
+    let mut tick = tokio::time::interval(PRODUCE_TICK);
+
+    loop {
+        tokio::select! {
+            biased;
+            _ = shutdown_watcher.recv() => return,
+            _ = tick.tick() => {},
+        }
+
+        let _producing = shared.chain.lock_sync().await;
+        match shared.chain.produce(current_time()) {
+            Ok(Some(block)) => println!(
+                "Produced block {} with {} transactions",
+                block.header.height,
+                block.transactions.len()
+            ),
+            Ok(None) => {}
+            Err(e) => println!("Failed to produce a block: {}", e),
+        }
+    }
+}
+
+/// Reports every switch of the main chain to another branch
+pub async fn log_reorgs(shared: Shared) {
+    let mut shutdown_watcher = shared.shutdown.subscribe();
+    let mut reorgs = shared.chain.subscribe();
+
+    loop {
+        let reorg = tokio::select! {
+            biased;
+            _ = shutdown_watcher.recv() => return,
+            reorg = reorgs.recv() => reorg,
+        };
+
+        match reorg {
+            Ok(reorg) => println!(
+                "Chain reorganized at block {}: {} blocks reverted, {} applied",
+                to_hex(&reorg.ancestor),
+                reorg.reverted.len(),
+                reorg.applied.len()
+            ),
+            Err(RecvError::Lagged(skipped)) => println!("Missed {} reorgs", skipped),
+            Err(RecvError::Closed) => return,
+        }
+    }
+}
+
+/// Keeps pulling the chain of the peer every block interval, ends once
+/// the session is gone or the peer sent an invalid chain
+async fn sync_chain(addr: SocketAddr, shared: &Shared) {
+    let mut interval = tokio::time::interval(Duration::from_secs(*BLOCK_INTERVAL));
+
+    loop {
+        interval.tick().await;
+
+        match sync_with(addr, shared).await {
+            Ok(()) => {}
+            Err(SyncError::Request(node_errors::RequestError::Timeout))
+            | Err(SyncError::Request(node_errors::RequestError::Rejected(_)))
+            | Err(SyncError::MissingBlocks) => {}
+            Err(SyncError::Invalid(e)) if e.is_local() => {
+                println!("Failed to sync with {}: {}", addr, e);
+            }
+            Err(SyncError::Request(_)) => return,
+            Err(e) => {
+                println!("Failed to sync with {}: {}", addr, e);
+                penalize(shared, &addr.ip(), &addr, Offence::InvalidBlock);
+                return;
+            }
+        }
+    }
+}
+
+/// Asks the peer for the headers of its main chain after the locator,
+/// returns them along with whether they reach its tip
+async fn fetch_headers(
+    addr: SocketAddr,
+    shared: &Shared,
+    locator: &[[u8; 32]],
+) -> Result<(Vec<block_models::Header>, bool), SyncError> {
+    let get_headers = packet_models::Request::get_headers(packet_models::GetHeadersRequest {
+        id: 0,
+        locator: locator.iter().map(|id| id.to_vec()).collect(),
+        max: None,
+    });
+    let headers = match request(&shared.registry, &addr, get_headers).await {
+        Ok(packet_models::Response::get_headers(r)) => r.headers,
+        Ok(_) => return Err(SyncError::UnexpectedResponse),
+        Err(e) => return Err(SyncError::Request(e)),
+    };
+    if headers.len() > MAX_HEADERS {
+        return Err(SyncError::UnexpectedResponse);
+    }
+
+    let complete = headers.len() < MAX_HEADERS;
+    Ok((headers, complete))
+}
+
+/// Checks that the headers form a chain following the parent
+fn check_branch(
+    shared: &Shared,
+    parent: &block_models::Header,
+    headers: &[block_models::Header],
+    now: u64,
+) -> Result<(), SyncError> {
+    let mut parent = parent;
+    for header in headers {
+        shared
+            .chain
+            .check_header(header, parent, now)
+            .map_err(SyncError::Invalid)?;
+        parent = header;
+    }
 
+    Ok(())
+}
+
+/// Downloads the headers the peer has after our tip, checks that they
+/// form a chain, then downloads and applies the blocks. The chain is only
+/// locked while blocks are applied, so that a slow peer doesn't hold up
+/// block production, and the round ends quietly if the chain changed
+/// during a download.
+async fn sync_with(addr: SocketAddr, shared: &Shared) -> Result<(), SyncError> {
+    loop {
+        let locator = shared.chain.locator().map_err(SyncError::Invalid)?;
+        let (headers, mut complete) = fetch_headers(addr, shared, &locator).await?;
+
+        // headers of our main chain are skipped, the rest is a branch
+        // growing from a block of it
+        let mut new = Vec::new();
+        for header in headers {
+            if !shared.chain.is_main(&header).map_err(SyncError::Invalid)? {
+                new.push(header);
+            }
+        }
+        let Some(first) = new.first() else {
+            return Ok(());
+        };
+        let ancestor = match first.parent.as_slice().try_into() {
+            Ok(parent) => shared.chain.header(&parent).map_err(SyncError::Invalid)?,
+            Err(_) => None,
+        };
+        let Some(ancestor) = ancestor else {
+            return Err(SyncError::UnexpectedResponse);
+        };
+        if !shared
+            .chain
+            .is_main(&ancestor)
+            .map_err(SyncError::Invalid)?
+        {
+            return Err(SyncError::UnexpectedResponse);
+        }
+
+        check_branch(shared, &ancestor, &new, current_time())?;
+
+        // a fork lighter over its first headers may still outweigh the main
+        // chain further on, so it is followed until it does or it ends
+        let extends = ancestor == shared.chain.tip().map_err(SyncError::Invalid)?;
+        let mut preferred = shared
+            .chain
+            .prefers(&ancestor, &new)
+            .map_err(SyncError::Invalid)?;
+        while !preferred && !complete {
+            let last = new.last().unwrap().clone();
+            let (more, more_complete) = fetch_headers(addr, shared, &[last.id()]).await?;
+            complete = more_complete;
+
+            // the peer switched to another branch meanwhile, the next
+            // round starts over
+            if more.first().is_some_and(|h| h.parent != last.id()) {
+                return Ok(());
+            }
+            check_branch(shared, &last, &more, current_time())?;
+            new.extend(more);
+            preferred = shared
+                .chain
+                .prefers(&ancestor, &new)
+                .map_err(SyncError::Invalid)?;
+        }
+        if !preferred {
+            let _syncing = shared.chain.lock_sync().await;
+            shared
+                .chain
+                .track(&ancestor, &new)
+                .map_err(SyncError::Invalid)?;
+            return Ok(());
+        }
+
+        // blocks extending the tip are applied as they come, another branch
+        // replaces the main chain at once
+        let mut blocks = Vec::new();
+        let mut parent = ancestor.clone();
+        let mut pending = new.as_slice();
+        while !pending.is_empty() {
+            let batch = &pending[..pending.len().min(MAX_BLOCKS)];
+            let get_blocks = packet_models::Request::get_blocks(packet_models::GetBlocksRequest {
+                id: 0,
+                ids: batch.iter().map(|h| h.id().to_vec()).collect(),
+            });
+            let batch_blocks = match request(&shared.registry, &addr, get_blocks).await {
+                Ok(packet_models::Response::get_blocks(r)) => r.blocks,
+                Ok(_) => return Err(SyncError::UnexpectedResponse),
+                Err(e) => return Err(SyncError::Request(e)),
+            };
+
+            // blocks which didn't fit the response are asked for again, no
+            // blocks at all may be a hiccup of the peer and is retried in
+            // the next round
+            if batch_blocks.is_empty() {
+                return Err(SyncError::MissingBlocks);
+            }
+            if batch_blocks.len() > batch.len()
+                || batch_blocks.iter().zip(batch).any(|(b, h)| b.header != *h)
+            {
+                return Err(SyncError::UnexpectedResponse);
+            }
+            pending = &pending[batch_blocks.len()..];
+
+            if extends {
+                let _syncing = shared.chain.lock_sync().await;
+                if shared.chain.tip().map_err(SyncError::Invalid)? != parent {
+                    return Ok(());
+                }
+                for block in &batch_blocks {
+                    shared
+                        .chain
+                        .apply_block(block, current_time())
+                        .map_err(SyncError::Invalid)?;
+                }
+                parent = batch_blocks.last().unwrap().header.clone();
+            } else {
+                blocks.extend(batch_blocks);
+            }
+        }
+
+        if !extends {
+            let _syncing = shared.chain.lock_sync().await;
+            // the main chain may have moved on while the blocks were
+            // downloading
+            if !shared
+                .chain
+                .is_main(&ancestor)
+                .map_err(SyncError::Invalid)?
+                || !shared
+                    .chain
+                    .prefers(&ancestor, &new)
+                    .map_err(SyncError::Invalid)?
+            {
+                return Ok(());
+            }
+
+            println!(
+                "Switching to the branch of {} forking at block {}",
+                addr, ancestor.height
+            );
+            shared
+                .chain
+                .reorganize(&ancestor, &blocks, current_time())
+                .map_err(SyncError::Invalid)?;
+        }
+
+        println!(
+            "Synced with {} up to block {}",
+            addr,
+            shared.chain.tip().map_err(SyncError::Invalid)?.height
+        );
+        if complete {
+            return Ok(());
+        }
+    }
+}
+
+/// Dials the address and sets up a session with the node behind it
+async fn establish_outbound(
+    addr: &SocketAddr,
+    slot: SlotGuard,
+    shared: &Shared,
+) -> Result<Connection, node_errors::NodeError> {
     let mut socket =
         if let Ok(Ok(s)) = tokio::time::timeout(*PEER_TIMEOUT, TcpStream::connect(addr)).await {
             s
@@ -386,108 +1209,490 @@ pub async fn handle_peer(
             return Err(node_errors::NodeError::new("Connection error".to_string()));
         };
 
-    // get cipher
-    let (nonce, shared) = match exchange_keys_client(&mut socket).await {
-        Ok(d) => d,
-        Err(e) => {
-            return Err(e);
+    // get session keys and the identity of the peer
+    let (mut session, identity) =
+        match tokio::time::timeout(*PEER_TIMEOUT, exchange_keys_client(&mut socket)).await {
+            Ok(Ok(d)) => d,
+            Ok(Err(e)) => {
+                return Err(e);
+            }
+            Err(_) => {
+                return Err(node_errors::NodeError::new(
+                    "Handshake timed out".to_string(),
+                ));
+            }
+        };
+
+    let hello = match tokio::time::timeout(
+        *PEER_TIMEOUT,
+        exchange_hello(&mut socket, &mut session, &identity),
+    )
+    .await
+    {
+        Ok(Ok(h)) => h,
+        Ok(Err(e)) => {
+            // the address leads back to this node, e.g. through a NAT
+            if e.is::<node_errors::SelfConnection>() {
+                println!("{} is an address of this node", addr);
+                shared.book.mark_own(*addr);
+            }
+            return Err(node_errors::NodeError::new(e.to_string()));
+        }
+        Err(_) => {
+            return Err(node_errors::NodeError::new("Hello timed out".to_string()));
         }
     };
-    let mut cipher = ChaCha20::new(shared.as_bytes().into(), &nonce.into());
+    observe_external(&hello, addr, shared);
+
+    let (reader, writer) = socket.into_split();
+    Ok(Connection {
+        id: NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed),
+        reader,
+        writer,
+        session,
+        addr: *addr,
+        peer_addr: *addr,
+        identity,
+        direction: peers_dump::Direction::Outbound,
+        hello,
+        tracker: RequestTracker::new(*REQUEST_TIMEOUT),
+        limits: rate_limits(),
+        slot,
+        last_received: Arc::new(Mutex::new(Instant::now())),
+        ping: None,
+        expired_pings: VecDeque::with_capacity(EXPIRED_PINGS),
+    })
+}
+
+/// Periodically asks an outbound peer for the nodes it knows
+/// and feeds the new ones to the dialer
+async fn discover_peers(addr: SocketAddr, shared: &Shared) {
+    let mut interval = tokio::time::interval(GET_NODES_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let get_nodes = packet_models::Request::get_nodes(packet_models::GetNodesRequest {
+            id: 0,
+            max: None,
+            family: None,
+        });
+        let response = match request(&shared.registry, &addr, get_nodes).await {
+            Ok(packet_models::Response::get_nodes(r)) => r,
+            Ok(_) => continue,
+            Err(node_errors::RequestError::Timeout)
+            | Err(node_errors::RequestError::Rejected(_)) => continue,
+            // the session is gone
+            Err(_) => return,
+        };
 
-    let mut waiting_response: HashSet<u64> = HashSet::with_capacity(20);
+        let mut addrs: Vec<SocketAddr> = Vec::new();
+        if let Some(dump) = response.ipv4 {
+            match parse_ipv4(&dump) {
+                Ok(parsed) => addrs.extend(parsed),
+                Err(e) => println!("Bad get_nodes response from {}: {}", addr, e),
+            }
+        }
+        if let Some(dump) = response.ipv6 {
+            match parse_ipv6(&dump) {
+                Ok(parsed) => addrs.extend(parsed),
+                Err(e) => println!("Bad get_nodes response from {}: {}", addr, e),
+            }
+        }
 
-    // announce
-    let id: u64 = rand::random();
+        for new_addr in addrs {
+            // the peer knows us as well
+            if !is_acceptable_peer_address(&new_addr) {
+                continue;
+            }
 
-    let body = models::addr2bin(&SERVER_ADDRESS);
-    let packet = packet_models::Packet::request(packet_models::Request::announce(
-        packet_models::AnnounceRequest { id, addr: body },
-    ));
+            if shared.book.add(
+                new_addr,
+                peers_dump::AddressSource::get_nodes,
+                Some(addr.ip()),
+            ) {
+                // nobody is listening only while shutting down
+                let _ = shared.new_peers_tx.send(new_addr);
+            }
+        }
+    }
+}
 
-    if let Err(e) = send_packet(&mut socket, &mut cipher, packet).await {
-        return Err(node_errors::NodeError::new(e.to_string()));
+/// Addresses which can be a remote peer, non-routable ones only when the
+/// network is configured to be local
+fn is_acceptable_peer_address(addr: &SocketAddr) -> bool {
+    if addr.ip().is_loopback() || addr.ip().is_unspecified() {
+        return false;
+    }
+
+    *ALLOW_NON_ROUTABLE || is_routable(&addr.ip())
+}
+
+/// Peers tell which address they see the node at, once enough of them
+/// agree it is announced instead of the configured one
+fn observe_external(hello: &packet_models::Hello, peer: &SocketAddr, shared: &Shared) {
+    let Some(observed) = hello.observed.as_deref().and_then(|o| bin2addr(o).ok()) else {
+        return;
     };
+    if !is_acceptable_peer_address(&observed) {
+        return;
+    }
 
-    // main loop
-    loop {
-        // TODO: write select
-        let packet = match receive_packet(&mut socket, &mut cipher, &mut rx_propagate).await {
-            Ok(p) => p,
-            Err(e) => {
-                return Err(node_errors::NodeError::new(e.to_string()));
-            }
-        };
+    if let Some(addr) = shared.external.report(peer.ip(), observed.ip()) {
+        println!("Learned external address: {}", addr);
+        shared.book.mark_own(addr);
+    }
+}
 
-        // handle packet
-        if process_packet(
-            &mut socket,
-            packet,
-            &mut waiting_response,
-            &mut cipher,
-            peers_mut.clone(),
-            &mut propagate,
-            &mut new_peers_tx,
-        )
-        .await
-        .is_err()
-        {
-            break;
+/// Connects to an announced address, it is only stored and gossiped once
+/// the node behind it completed a handshake with the announced identity
+async fn probe_announce(
+    addr: SocketAddr,
+    identity: Option<[u8; 32]>,
+    origin: u64,
+    from: IpAddr,
+    packet: packet_models::Packet,
+    shared: Shared,
+) {
+    if shared.bans.is_banned(&addr.ip(), current_time()) {
+        return;
+    }
+
+    // a connected or already tried node doesn't have to be probed, probing
+    // a connected node would make it drop a session as a duplicate
+    let known = shared
+        .registry
+        .get(&addr)
+        .map(|handle| handle.identity)
+        .or_else(|| shared.book.get(&addr).and_then(|info| info.identity));
+
+    let (verified, probed) = match known {
+        Some(verified) => (verified, false),
+        None => {
+            let Some(slot) = shared
+                .slots
+                .acquire(addr.ip(), peers_dump::Direction::Outbound)
+            else {
+                return;
+            };
+
+            let mut rx = shared.shutdown.subscribe();
+            let conn = tokio::select! {
+                _ = rx.recv() => return,
+                res = establish_outbound(&addr, slot, &shared) => match res {
+                    Ok(conn) => conn,
+                    Err(e) => {
+                        println!("Announced address {} is unreachable: {}", addr, e);
+                        return;
+                    }
+                },
+            };
+
+            (conn.identity.to_bytes(), true)
         }
+    };
+
+    if identity.is_some_and(|i| i != verified) {
+        println!("Announced identity of {} doesn't match the node", addr);
+        return;
+    }
+
+    shared.propagate.forward(origin, packet);
+    let added = shared
+        .book
+        .add(addr, peers_dump::AddressSource::announce, Some(from));
+    if probed {
+        shared
+            .book
+            .mark_success(&addr, verified, peers_dump::Direction::Outbound);
+    }
+    if added {
+        let _ = shared.new_peers_tx.send(addr);
+    }
+}
+
+/// Offence committed by sending a frame which failed to be received,
+/// connection errors are nobody's fault
+fn frame_offence(e: &(dyn std::error::Error + 'static)) -> Option<Offence> {
+    if e.is::<node_errors::PacketTooLarge>() {
+        Some(Offence::OversizedPacket)
+    } else if e.is::<node_errors::MalformedPacket>()
+        || e.is::<node_errors::AuthenticationFailed>()
+        || e.is::<node_errors::UnexpectedCounter>()
+    {
+        Some(Offence::MalformedPacket)
+    } else {
+        None
+    }
+}
+
+/// Adds the offence to the score of the peer, banned peers are forgotten,
+/// so they aren't dialed or handed out to other nodes anymore.
+/// Returns true if the peer got banned
+fn penalize(shared: &Shared, ip: &IpAddr, peer_addr: &SocketAddr, offence: Offence) -> bool {
+    if !shared.bans.misbehaved(ip, offence, current_time()) {
+        return false;
+    }
+
+    println!("Banning {} for {:?}", peer_addr, offence);
+    shared.book.remove(peer_addr);
+    true
+}
+
+/// Penalizes the peer of the session, ending the session once it's banned
+fn misbehaved(
+    conn: &Connection,
+    shared: &Shared,
+    offence: Offence,
+) -> Result<(), node_errors::NodeError> {
+    if penalize(shared, &conn.addr.ip(), &conn.peer_addr, offence) {
+        return Err(node_errors::NodeError::new("Peer was banned".to_string()));
     }
 
     Ok(())
 }
 
+/// Sends the response to a request, or the error it failed with
+async fn respond(
+    conn: &mut Connection,
+    id: u64,
+    response: Result<packet_models::Response, packet_models::ErrorCode>,
+) -> ResultSmall<()> {
+    let error = |code| packet_models::Packet::error(packet_models::ErrorR { code, id: Some(id) });
+    let packet = match response {
+        Ok(r) => packet_models::Packet::response(r),
+        Err(code) => error(code),
+    };
+
+    // a response which doesn't fit a frame fails the request, not the
+    // session
+    match send_packet(&mut conn.writer, &mut conn.session.send, packet).await {
+        Err(e) if e.is::<node_errors::PacketTooLarge>() => {}
+        result => return result,
+    }
+    let packet = error(packet_models::ErrorCode::Internal);
+    send_packet(&mut conn.writer, &mut conn.session.send, packet).await
+}
+
+/// Response with the transaction and the proof of its inclusion, checked
+/// the way a light client would, a proof which doesn't hold means a
+/// corrupt ledger and isn't served
+fn transaction_response(
+    id: u64,
+    inclusion: Inclusion,
+) -> Result<packet_models::Response, packet_models::ErrorCode> {
+    let response = packet_models::GetTransactionResponse {
+        id,
+        transaction: inclusion.transaction,
+        block: inclusion.header.id().to_vec(),
+        index: inclusion.index,
+        proof: inclusion.proof.iter().map(|h| h.to_vec()).collect(),
+    };
+    if !response.verify(&inclusion.header) {
+        println!(
+            "Stored block {} doesn't prove its transaction {}",
+            to_hex(&response.block),
+            inclusion.index
+        );
+        return Err(packet_models::ErrorCode::Internal);
+    }
+
+    Ok(packet_models::Response::get_transaction(response))
+}
+
+/// The gossip id is derived from the transaction, so that every node
+/// forwards a transaction once, no matter who submitted it
+fn transaction_gossip_id(id: &[u8; 32]) -> u64 {
+    u64::from_le_bytes(id[..8].try_into().unwrap())
+}
+
+/// Id the request is flooded under, if it is gossip
+fn gossip_id(request: &packet_models::Request) -> Option<u64> {
+    match request {
+        packet_models::Request::announce(p) => Some(p.id),
+        packet_models::Request::announce_transaction(p) => {
+            Some(transaction_gossip_id(&p.transaction.id()))
+        }
+        _ => None,
+    }
+}
+
+/// Adds the transaction to the mempool, checking it against the ledger,
+/// and gossips it to every other session
+fn accept_transaction(
+    conn: &mut Connection,
+    transaction: &transaction_models::Transaction,
+    shared: &Shared,
+) -> Result<[u8; 32], MempoolError> {
+    let sender = <[u8; 32]>::try_from(transaction.sender.as_slice())
+        .map_err(|_| MempoolError::Invalid(models_errors::InvalidTransaction::BadKey))?;
+    let account = shared
+        .ledger
+        .account(&sender)
+        .map_err(MempoolError::Ledger)?
+        .unwrap_or_default();
+
+    let id = shared
+        .mempool
+        .insert(transaction.clone(), account, current_time())?;
+    conn.slot.record_useful();
+
+    let gossip_id = transaction_gossip_id(&id);
+    let packet = packet_models::Packet::request(packet_models::Request::announce_transaction(
+        packet_models::AnnounceTransactionRequest {
+            id: gossip_id,
+            transaction: transaction.clone(),
+        },
+    ));
+    shared.propagate.publish(conn.id, gossip_id, packet);
+
+    Ok(id)
+}
+
+/// Error code a rejected transaction is answered with
+fn rejection_code(e: &MempoolError) -> packet_models::ErrorCode {
+    match e {
+        MempoolError::Full => packet_models::ErrorCode::MempoolFull,
+        MempoolError::Ledger(e) => {
+            println!("Failed to read the ledger: {}", e);
+            packet_models::ErrorCode::Internal
+        }
+        _ => packet_models::ErrorCode::InvalidTransaction,
+    }
+}
+
 async fn process_packet(
-    socket: &mut TcpStream,
+    conn: &mut Connection,
     packet: packet_models::Packet,
-    waiting_response: &mut HashSet<u64>,
-    cipher: &mut ChaCha20,
-    peers_mut: Arc<Mutex<HashSet<SocketAddr>>>,
-    propagate: &mut Sender<packet_models::Packet>,
-    new_peers_tx: &mut Sender<SocketAddr>,
+    shared: &Shared,
 ) -> ResultSmall<()> {
     match &packet {
+        // gossip which already went through the node is dropped for free,
+        // so that relays aren't limited for what others sent first
+        packet_models::Packet::request(r)
+            if gossip_id(r).is_some_and(|id| shared.propagate.is_seen(id)) => {}
+        // a peer must not flood the node or use it to amplify announces,
+        // the address book must not be enumerated by asking repeatedly
+        packet_models::Packet::request(r) if !conn.limits.allow(r, Instant::now()) => {
+            let response_packet = packet_models::Packet::error(packet_models::ErrorR {
+                code: packet_models::ErrorCode::RateLimited,
+                id: Some(r.id()),
+            });
+            send_packet(&mut conn.writer, &mut conn.session.send, response_packet).await?;
+        }
         packet_models::Packet::request(r) => match r {
             packet_models::Request::announce(p) => {
-                let addr = bin2addr(&p.addr)?;
+                // verify address is valid and routable, and the identity is a key
+                let addr = bin2addr(&p.addr).ok();
+                let identity = p
+                    .identity
+                    .as_deref()
+                    .map(<[u8; 32]>::try_from)
+                    .transpose()
+                    .ok();
+                let valid = addr.zip(identity).filter(|(addr, identity)| {
+                    // a peer announcing itself has to use the key it proved
+                    *addr != conn.peer_addr
+                        || identity.is_none_or(|i| i == conn.identity.to_bytes())
+                });
+                // a non-routable address is only useless, e.g. a node behind
+                // a NAT which doesn't know its external address yet
+                let acceptable = valid.filter(|(addr, _)| is_acceptable_peer_address(addr));
+                let Some((addr, identity)) = acceptable else {
+                    if valid.is_none() {
+                        misbehaved(conn, shared, Offence::BadAddress)?;
+                    }
 
-                // verify address is not loopback
-                if addr.ip().is_loopback() || addr.ip().is_unspecified() {
                     let response_packet = packet_models::Packet::error(packet_models::ErrorR {
                         code: packet_models::ErrorCode::BadAddress,
+                        id: Some(p.id),
                     });
-                    send_packet(socket, cipher, response_packet).await?;
+                    send_packet(&mut conn.writer, &mut conn.session.send, response_packet).await?;
 
                     return Ok(());
-                }
+                };
 
-                let mut peers = peers_mut.lock().unwrap();
-                let res = peers.insert(addr);
-                drop(peers);
+                if *PROBE_ANNOUNCES {
+                    if !shared.propagate.mark_seen(p.id) {
+                        return Ok(());
+                    }
+                    conn.slot.record_useful();
+
+                    tokio::spawn(probe_announce(
+                        addr,
+                        identity,
+                        conn.id,
+                        conn.addr.ip(),
+                        packet.clone(),
+                        shared.clone(),
+                    ));
+                    return Ok(());
+                }
 
-                if res {
-                    propagate.send(packet)?;
-                    new_peers_tx.send(addr)?;
+                // every announce is flooded once, known ids stop here
+                if !shared.propagate.publish(conn.id, p.id, packet.clone()) {
+                    return Ok(());
+                }
+                conn.slot.record_useful();
+
+                if shared.book.add(
+                    addr,
+                    peers_dump::AddressSource::announce,
+                    Some(conn.addr.ip()),
+                ) {
+                    shared.new_peers_tx.send(addr)?;
                 }
             }
-            packet_models::Request::get_amount(p) => {}
+            packet_models::Request::get_amount(p) => {
+                let response = match <[u8; 32]>::try_from(p.address.as_slice()) {
+                    Ok(address) => match shared.ledger.account(&address) {
+                        Ok(Some(account)) => Ok(packet_models::Response::get_amount(
+                            packet_models::GetAmountReponse {
+                                id: p.id,
+                                amount: account.balance,
+                                nonce: account.nonce,
+                            },
+                        )),
+                        Ok(None) => Err(packet_models::ErrorCode::NotFound),
+                        Err(e) => {
+                            println!("Failed to read the ledger: {}", e);
+                            Err(packet_models::ErrorCode::Internal)
+                        }
+                    },
+                    Err(_) => Err(packet_models::ErrorCode::ParseError),
+                };
+                respond(conn, p.id, response).await?;
+            }
+            packet_models::Request::get_transactions(p) => {
+                let response = match <[u8; 32]>::try_from(p.address.as_slice()) {
+                    Ok(address) => match shared
+                        .ledger
+                        .transactions_of(&address, MAX_GET_TRANSACTIONS_RESPONSE)
+                    {
+                        Ok(ids) => Ok(packet_models::Response::get_transactions(
+                            packet_models::GetTransactionsResponse {
+                                id: p.id,
+                                tx_ids: ids.iter().map(|id| id.to_vec()).collect(),
+                            },
+                        )),
+                        Err(e) => {
+                            println!("Failed to read the ledger: {}", e);
+                            Err(packet_models::ErrorCode::Internal)
+                        }
+                    },
+                    Err(_) => Err(packet_models::ErrorCode::ParseError),
+                };
+                respond(conn, p.id, response).await?;
+            }
             packet_models::Request::get_nodes(p) => {
-                let mut peers_cloned: Box<[SocketAddr]>;
-                {
-                    // clone peers into vec
-                    let peers = peers_mut.lock().unwrap();
-                    peers_cloned = vec![*SERVER_ADDRESS; peers.len()].into_boxed_slice();
-                    for (index, peer) in peers.iter().enumerate() {
-                        let cell = unsafe { peers_cloned.get_unchecked_mut(index) };
-                        *cell = *peer;
-                    }
-                    drop(peers);
-                }
+                let max = p
+                    .max
+                    .map_or(MAX_GET_NODES_RESPONSE, |m| m as usize)
+                    .min(MAX_GET_NODES_RESPONSE);
+                let sampled = shared.book.sample(max, p.family);
 
                 // dump ipv4 and ipv6 addresses in u8 vecs separately
-                let (ipv4, ipv6) = dump_addresses(&peers_cloned);
+                let (ipv4, ipv6) = dump_addresses(&sampled);
 
                 let packet = packet_models::Packet::response(packet_models::Response::get_nodes(
                     packet_models::GetNodesReponse {
@@ -496,67 +1701,287 @@ async fn process_packet(
                         ipv6,
                     },
                 ));
-                send_packet(socket, cipher, packet).await?;
+                send_packet(&mut conn.writer, &mut conn.session.send, packet).await?;
+            }
+            packet_models::Request::get_transaction(p) => {
+                let response = match <[u8; 32]>::try_from(p.tx_id.as_slice()) {
+                    Ok(tx_id) => match shared.chain.transaction(&tx_id) {
+                        Ok(Some(inclusion)) => transaction_response(p.id, inclusion),
+                        Ok(None) => Err(packet_models::ErrorCode::NotFound),
+                        Err(e) => {
+                            println!("Failed to read the chain: {}", e);
+                            Err(packet_models::ErrorCode::Internal)
+                        }
+                    },
+                    Err(_) => Err(packet_models::ErrorCode::ParseError),
+                };
+                respond(conn, p.id, response).await?;
+            }
+            packet_models::Request::submit_transaction(p) => {
+                let response = match accept_transaction(conn, &p.transaction, shared) {
+                    // submitting a transaction twice is harmless
+                    Ok(_) | Err(MempoolError::AlreadyKnown) => {
+                        Ok(packet_models::Response::submit_transaction(
+                            packet_models::SubmitTransactionResponse {
+                                id: p.id,
+                                tx_id: p.transaction.id().to_vec(),
+                            },
+                        ))
+                    }
+                    Err(e) => Err(rejection_code(&e)),
+                };
+                respond(conn, p.id, response).await?;
+            }
+            packet_models::Request::announce_transaction(p) => {
+                match accept_transaction(conn, &p.transaction, shared) {
+                    Ok(_) | Err(MempoolError::AlreadyKnown) => {}
+                    Err(e) => {
+                        // balance and nonce depend on the state of the
+                        // relay, only a bad signature is its fault
+                        if let MempoolError::Invalid(_) = e {
+                            misbehaved(conn, shared, Offence::InvalidTransaction)?;
+                        }
+                        respond(conn, p.id, Err(rejection_code(&e))).await?;
+                    }
+                }
             }
-            packet_models::Request::get_transaction(p) => {}
+            packet_models::Request::get_headers(p) => {
+                let max = p.max.map_or(MAX_HEADERS, |m| m as usize);
+                let response = match shared.chain.headers_after(&p.locator, max) {
+                    Ok(headers) => Ok(packet_models::Response::get_headers(
+                        packet_models::GetHeadersResponse { id: p.id, headers },
+                    )),
+                    Err(e) => {
+                        println!("Failed to read the chain: {}", e);
+                        Err(packet_models::ErrorCode::Internal)
+                    }
+                };
+                respond(conn, p.id, response).await?;
+            }
+            packet_models::Request::get_blocks(p) => {
+                let max_size = MAX_FRAME_SIZE.min(*MAX_PAYLOAD_SIZE) - RESPONSE_HEADROOM;
+                let response = match shared.chain.blocks(&p.ids, max_size) {
+                    Ok(blocks) => Ok(packet_models::Response::get_blocks(
+                        packet_models::GetBlocksResponse { id: p.id, blocks },
+                    )),
+                    Err(e) => {
+                        println!("Failed to read the chain: {}", e);
+                        Err(packet_models::ErrorCode::Internal)
+                    }
+                };
+                respond(conn, p.id, response).await?;
+            }
+            packet_models::Request::ping(p) => {
+                let packet = packet_models::Packet::response(packet_models::Response::pong(
+                    packet_models::PongResponse {
+                        id: p.id,
+                        nonce: p.nonce,
+                    },
+                ));
+                send_packet(&mut conn.writer, &mut conn.session.send, packet).await?;
+            }
+        },
+        packet_models::Packet::response(packet_models::Response::pong(p)) => {
+            match conn.ping.take() {
+                Some(ping) if ping.id == p.id && ping.nonce == p.nonce => {
+                    shared.book.record_rtt(&conn.peer_addr, ping.sent.elapsed());
+                }
+                ping => {
+                    conn.ping = ping;
+
+                    // a pong which came too late is dropped quietly
+                    match conn.expired_pings.iter().position(|id| *id == p.id) {
+                        Some(index) => {
+                            conn.expired_pings.remove(index);
+                        }
+                        None => misbehaved(conn, shared, Offence::UnsolicitedResponse)?,
+                    }
+                }
+            }
+        }
+        packet_models::Packet::response(r) => match conn.tracker.resolve(r.id(), Ok(r.clone())) {
+            Resolution::Delivered => conn.slot.record_useful(),
+            Resolution::Late => {}
+            Resolution::Unsolicited => misbehaved(conn, shared, Offence::UnsolicitedResponse)?,
         },
-        packet_models::Packet::response(r) => {}
-        packet_models::Packet::error(e) => {}
+        packet_models::Packet::error(e) => {
+            // errors without an id are about the session, errors for
+            // untracked packets (e.g. forwarded announces) are not penalized
+            if let Some(id) = e.id {
+                conn.tracker
+                    .resolve(id, Err(node_errors::RequestError::Rejected(e.code)));
+            }
+        }
+        packet_models::Packet::hello(_) => {
+            misbehaved(conn, shared, Offence::MalformedPacket)?;
+            return Err(node_errors::NodeError::new("Unexpected hello".to_string()).into());
+        }
     }
 
     Ok(())
 }
 
-pub async fn connect_new_peers(
-    shutdown: Sender<u8>,
-    peers_mut: Arc<Mutex<HashSet<SocketAddr>>>,
-    propagate: Sender<packet_models::Packet>,
-    new_peers_tx: Sender<SocketAddr>,
-) {
-    let mut shutdown_watcher = shutdown.subscribe();
-    let mut new_peers_rx = new_peers_tx.subscribe();
+/// Sends the request to a connected peer and waits for its response
+pub async fn request(
+    registry: &SessionRegistry,
+    peer: &SocketAddr,
+    request: packet_models::Request,
+) -> Result<packet_models::Response, node_errors::RequestError> {
+    let handle = registry
+        .get(peer)
+        .ok_or(node_errors::RequestError::NotConnected)?;
+
+    let (responder, response) = oneshot::channel();
+    handle
+        .commands
+        .send(Command::Request { request, responder })
+        .await
+        .map_err(|_| node_errors::RequestError::Closed)?;
+
+    // the responder is dropped if the session ends before the response
+    response
+        .await
+        .map_err(|_| node_errors::RequestError::Closed)?
+}
+
+/// Keeps the target amount of outbound connections, dialing known
+/// addresses whenever a slot is free
+pub async fn manage_connections(shared: Shared) {
+    let mut shutdown_watcher = shared.shutdown.subscribe();
+    let mut new_peers_rx = shared.new_peers_tx.subscribe();
+    let (events_tx, mut events) = mpsc::channel(DIAL_EVENTS_CAPACITY);
+    let mut manager =
+        ConnectionManager::new(*TARGET_OUTBOUND, *EVICTION_FAILURES, *EVICTION_WINDOW);
+    let mut tick = tokio::time::interval(MANAGER_TICK);
 
-    tokio::select! {
-        _ = shutdown_watcher.recv() => {},
-        _ = connect_new_peers_wrapped(
-            peers_mut,
-            propagate,
-            shutdown.clone(),
-            &mut new_peers_rx,
-            new_peers_tx
-        ) => {}
-    }
-}
-
-async fn connect_new_peers_wrapped(
-    peers_mut: Arc<Mutex<HashSet<SocketAddr>>>,
-    propagate: Sender<packet_models::Packet>,
-    shutdown: Sender<u8>,
-    new_peers_rx: &mut Receiver<SocketAddr>,
-    new_peers_tx: Sender<SocketAddr>,
-) {
     loop {
-        let peer_addr = match new_peers_rx.recv().await {
-            Ok(p) => p,
-            Err(_) => {
-                continue;
+        // sessions report their end while shutting down, nothing may be
+        // dialed after that
+        tokio::select! {
+            biased;
+            _ = shutdown_watcher.recv() => return,
+            _ = tick.tick() => {},
+            // a new address may take a free slot right away
+            _ = new_peers_rx.recv() => {},
+            event = events.recv() => {
+                // the manager keeps a sender, the channel can't close
+                let Some((addr, event)) = event else {
+                    return;
+                };
+
+                if manager.handle(addr, event, Instant::now()) {
+                    println!("Forgetting {} after repeated failures", addr);
+                    shared.book.remove(&addr);
+                }
             }
-        };
+        }
+
+        let now = current_time();
+        let candidates: Vec<SocketAddr> = shared
+            .book
+            .addresses()
+            .into_iter()
+            .filter(|addr| !shared.bans.is_banned(&addr.ip(), now))
+            // peers which dialed us already have a session
+            .filter(|addr| !shared.registry.contains(addr))
+            .collect();
+
+        for addr in manager.select(candidates, Instant::now()) {
+            tokio::spawn(connect_to_peer(addr, shared.clone(), events_tx.clone()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod frame_limits_tests {
+    use super::*;
+
+    fn session_pair() -> (Session, Session) {
+        let mut a = Handshake::new(Role::Initiator, StaticSecret::random_from_rng(OsRng));
+        let mut b = Handshake::new(Role::Responder, StaticSecret::random_from_rng(OsRng));
+
+        b.read_message_1(&a.write_message_1());
+        a.read_message_2(&b.write_message_2().unwrap().try_into().unwrap())
+            .unwrap();
+        b.read_message_3(&a.write_message_3().unwrap().try_into().unwrap())
+            .unwrap();
 
-        // // probably need to properly check if peer has
-        // // already being connected to
-        // let mut peers = peers_mut.lock().unwrap();
-        // if !peers.insert(peer_addr) {
-        //     continue;
-        // }
-        // drop(peers);
-
-        tokio::spawn(connect_to_peer(
-            peer_addr,
-            peers_mut.clone(),
-            shutdown.clone(),
-            propagate.clone(),
-            new_peers_tx.clone(),
+        (a.finish().unwrap().0, b.finish().unwrap().0)
+    }
+
+    #[test]
+    fn decompress_bomb_test() {
+        let data = zstd::encode_all(Cursor::new(vec![0u8; 1024 * 1024]), 3).unwrap();
+
+        let res = decompress(&data, 1024);
+        assert!(res.unwrap_err().is::<node_errors::PacketTooLarge>());
+
+        assert_eq!(decompress(&data, 1024 * 1024).unwrap().len(), 1024 * 1024);
+    }
+
+    #[tokio::test]
+    async fn oversized_frame_test() {
+        let (_, mut b) = session_pair();
+
+        // only the header is sent, the body must not even be waited for
+        let mut header = [0u8; session::FRAME_HEADER_SIZE];
+        header[..4].copy_from_slice(&u32::MAX.to_be_bytes());
+
+        let res = receive_packet(&mut &header[..], &mut b.recv).await;
+        assert!(res.unwrap_err().is::<node_errors::PacketTooLarge>());
+    }
+
+    #[tokio::test]
+    async fn send_oversized_test() {
+        use rand_core::RngCore;
+
+        let (mut a, mut b) = session_pair();
+        let ids = (0..40_000)
+            .map(|_| {
+                let mut id = vec![0u8; 32];
+                OsRng.fill_bytes(&mut id);
+                id
+            })
+            .collect();
+        let packet = packet_models::Packet::request(packet_models::Request::get_blocks(
+            packet_models::GetBlocksRequest { id: 1, ids },
         ));
+
+        // nothing is written and the session stays usable
+        let mut wire: Vec<u8> = Vec::new();
+        let res = send_packet(&mut wire, &mut a.send, packet).await;
+        assert!(res.unwrap_err().is::<node_errors::PacketTooLarge>());
+        assert!(wire.is_empty());
+
+        let packet = packet_models::Packet::error(packet_models::ErrorR {
+            code: packet_models::ErrorCode::Internal,
+            id: Some(1),
+        });
+        send_packet(&mut wire, &mut a.send, packet.clone())
+            .await
+            .unwrap();
+        let received = receive_packet(&mut wire.as_slice(), &mut b.recv)
+            .await
+            .unwrap();
+        assert_eq!(received, packet);
+    }
+
+    #[tokio::test]
+    async fn roundtrip_test() {
+        let (mut a, mut b) = session_pair();
+        let packet = packet_models::Packet::error(packet_models::ErrorR {
+            code: packet_models::ErrorCode::ParseError,
+            id: None,
+        });
+
+        let mut wire: Vec<u8> = Vec::new();
+        send_packet(&mut wire, &mut a.send, packet.clone())
+            .await
+            .unwrap();
+
+        let received = receive_packet(&mut wire.as_slice(), &mut b.recv)
+            .await
+            .unwrap();
+        assert_eq!(received, packet);
     }
 }
@@ -0,0 +1,163 @@
+use crate::models::packet_models::Request;
+use tokio::time::Instant;
+
+/// How many messages of a kind a peer may send
+#[derive(Clone, Copy, Debug)]
+pub struct Budget {
+    /// messages which may be sent at once
+    pub burst: u32,
+    /// messages per minute allowed in the long run
+    pub per_minute: u32,
+}
+
+/// Budgets of the requests peers send
+#[derive(Clone, Copy, Debug)]
+pub struct Budgets {
+    /// all requests together
+    pub requests: Budget,
+    pub announce: Budget,
+    pub get_nodes: Budget,
+    /// submitted and announced transactions
+    pub transactions: Budget,
+    /// header and block requests
+    pub sync: Budget,
+}
+
+/// Allows `burst` messages at once, refilled at a steady rate
+pub struct TokenBucket {
+    capacity: f64,
+    per_second: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(budget: Budget, now: Instant) -> TokenBucket {
+        TokenBucket {
+            capacity: budget.burst as f64,
+            per_second: budget.per_minute as f64 / 60.0,
+            tokens: budget.burst as f64,
+            updated: now,
+        }
+    }
+
+    /// Takes a token if there is one
+    pub fn try_take(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_second).min(self.capacity);
+        self.updated = now;
+
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+/// Rate limits of the requests coming from one connection
+pub struct RateLimits {
+    requests: TokenBucket,
+    announce: TokenBucket,
+    get_nodes: TokenBucket,
+    transactions: TokenBucket,
+    sync: TokenBucket,
+}
+
+impl RateLimits {
+    pub fn new(budgets: Budgets, now: Instant) -> RateLimits {
+        RateLimits {
+            requests: TokenBucket::new(budgets.requests, now),
+            announce: TokenBucket::new(budgets.announce, now),
+            get_nodes: TokenBucket::new(budgets.get_nodes, now),
+            transactions: TokenBucket::new(budgets.transactions, now),
+            sync: TokenBucket::new(budgets.sync, now),
+        }
+    }
+
+    /// Whether the request fits the budgets, refused requests count
+    /// against the overall budget as well
+    pub fn allow(&mut self, request: &Request, now: Instant) -> bool {
+        if !self.requests.try_take(now) {
+            return false;
+        }
+
+        match request {
+            Request::announce(_) => self.announce.try_take(now),
+            Request::get_nodes(_) => self.get_nodes.try_take(now),
+            Request::submit_transaction(_) | Request::announce_transaction(_) => {
+                self.transactions.try_take(now)
+            }
+            Request::get_headers(_) | Request::get_blocks(_) => self.sync.try_take(now),
+            Request::get_amount(_)
+            | Request::get_transaction(_)
+            | Request::get_transactions(_)
+            | Request::ping(_) => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod rate_limit_tests {
+    use super::*;
+    use crate::models::packet_models::{AnnounceRequest, GetAmountRequest};
+    use tokio::time::Duration;
+
+    #[test]
+    fn token_bucket_test() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(
+            Budget {
+                burst: 2,
+                per_minute: 60,
+            },
+            now,
+        );
+
+        assert!(bucket.try_take(now));
+        assert!(bucket.try_take(now));
+        assert!(!bucket.try_take(now));
+
+        // one token a second, never more than the burst
+        assert!(bucket.try_take(now + Duration::from_secs(1)));
+        assert!(!bucket.try_take(now + Duration::from_secs(1)));
+        assert!(bucket.try_take(now + Duration::from_secs(60)));
+        assert!(bucket.try_take(now + Duration::from_secs(60)));
+        assert!(!bucket.try_take(now + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn per_type_test() {
+        let now = Instant::now();
+        let budget = |burst| Budget {
+            burst,
+            per_minute: 1,
+        };
+        let mut limits = RateLimits::new(
+            Budgets {
+                requests: budget(3),
+                announce: budget(1),
+                get_nodes: budget(1),
+                transactions: budget(1),
+                sync: budget(1),
+            },
+            now,
+        );
+        let announce = Request::announce(AnnounceRequest {
+            id: 0,
+            addr: Vec::new(),
+            identity: None,
+        });
+        let get_amount = Request::get_amount(GetAmountRequest {
+            id: 0,
+            address: vec![1; 32],
+        });
+
+        assert!(limits.allow(&announce, now));
+        assert!(!limits.allow(&announce, now));
+
+        // other requests have their own budget, until the overall one is spent
+        assert!(limits.allow(&get_amount, now));
+        assert!(!limits.allow(&get_amount, now));
+    }
+}
@@ -0,0 +1,198 @@
+use crate::errors::node_errors::DuplicateSession;
+use crate::models::peers_dump::Direction;
+use crate::requests::Command;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, Notify};
+use tokio::time::{Duration, Instant};
+
+/// Way to reach a running session from other tasks
+#[derive(Clone)]
+pub struct SessionHandle {
+    pub id: u64,
+    /// address the peer accepts connections on
+    pub addr: SocketAddr,
+    pub identity: [u8; 32],
+    pub direction: Direction,
+    /// nonce from the hello of the peer, a restarted peer has another one
+    pub nonce: u64,
+    /// when the last packet from the peer arrived
+    pub last_received: Arc<Mutex<Instant>>,
+    pub commands: mpsc::Sender<Command>,
+    /// notified when a duplicate session with the peer took over
+    pub superseded: Arc<Notify>,
+}
+
+/// Live sessions keyed by the identity of the peer, so that there is
+/// at most one session with every node
+#[derive(Clone)]
+pub struct SessionRegistry {
+    /// identity of this node
+    local: [u8; 32],
+    /// silence after which a session may be taken over, a live one is
+    /// pinged and answers sooner
+    liveness: Duration,
+    sessions: Arc<Mutex<HashMap<[u8; 32], SessionHandle>>>,
+}
+
+impl SessionRegistry {
+    pub fn new(local: [u8; 32], liveness: Duration) -> SessionRegistry {
+        SessionRegistry {
+            local,
+            liveness,
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Identity of the node which dialed the session
+    fn initiator(&self, handle: &SessionHandle) -> [u8; 32] {
+        match handle.direction {
+            Direction::Outbound => self.local,
+            Direction::Inbound => handle.identity,
+        }
+    }
+
+    /// Registers the session unless there already is one with the same
+    /// peer. When both nodes dialed each other, the session dialed by the
+    /// node with the smaller identity survives, both sides agree on it
+    /// without talking. Otherwise the older session is kept, unless it is
+    /// dead: the peer restarted or went silent, e.g. with a half-closed
+    /// connection.
+    pub fn register(&self, handle: SessionHandle) -> Result<(), DuplicateSession> {
+        let mut sessions = self.sessions.lock().unwrap();
+
+        if let Some(old) = sessions.get(&handle.identity) {
+            let dead = old.nonce != handle.nonce
+                || old.last_received.lock().unwrap().elapsed() >= self.liveness;
+            if !dead && self.initiator(&handle) >= self.initiator(old) {
+                return Err(DuplicateSession { addr: handle.addr });
+            }
+            old.superseded.notify_one();
+        }
+
+        sessions.insert(handle.identity, handle);
+        Ok(())
+    }
+
+    /// Removes the session, unless the peer was taken over by a newer one
+    pub fn unregister(&self, identity: &[u8; 32], id: u64) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if sessions.get(identity).map(|h| h.id) == Some(id) {
+            sessions.remove(identity);
+        }
+    }
+
+    /// Session with the node listening on `addr`
+    pub fn get(&self, addr: &SocketAddr) -> Option<SessionHandle> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .values()
+            .find(|h| h.addr == *addr)
+            .cloned()
+    }
+
+    pub fn contains(&self, addr: &SocketAddr) -> bool {
+        self.get(addr).is_some()
+    }
+}
+
+#[cfg(test)]
+mod registry_tests {
+    use super::*;
+
+    const LIVENESS: Duration = Duration::from_secs(60);
+
+    fn handle(id: u64, identity: u8, direction: Direction) -> SessionHandle {
+        SessionHandle {
+            id,
+            addr: SocketAddr::new([10, 0, 0, identity].into(), 5050),
+            identity: [identity; 32],
+            direction,
+            nonce: 0,
+            last_received: Arc::new(Mutex::new(Instant::now())),
+            commands: mpsc::channel(1).0,
+            superseded: Arc::new(Notify::new()),
+        }
+    }
+
+    #[test]
+    fn simultaneous_dial_test() {
+        // nodes 1 and 2 dialed each other, the session dialed by 1 survives
+        let one = SessionRegistry::new([1; 32], LIVENESS);
+        assert!(one.register(handle(0, 2, Direction::Inbound)).is_ok());
+        assert!(one.register(handle(1, 2, Direction::Outbound)).is_ok());
+        assert_eq!(
+            one.get(&handle(0, 2, Direction::Inbound).addr).unwrap().id,
+            1
+        );
+
+        // the other order of events gives the same result
+        let two = SessionRegistry::new([2; 32], LIVENESS);
+        assert!(two.register(handle(0, 1, Direction::Inbound)).is_ok());
+        assert!(two.register(handle(1, 1, Direction::Outbound)).is_err());
+        assert_eq!(
+            two.get(&handle(0, 1, Direction::Inbound).addr).unwrap().id,
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn superseded_test() {
+        let registry = SessionRegistry::new([1; 32], LIVENESS);
+        let old = handle(0, 2, Direction::Inbound);
+        registry.register(old.clone()).unwrap();
+        registry
+            .register(handle(1, 2, Direction::Outbound))
+            .unwrap();
+
+        tokio::time::timeout(std::time::Duration::from_secs(1), old.superseded.notified())
+            .await
+            .unwrap();
+
+        // the replaced session doesn't remove its successor
+        registry.unregister(&[2; 32], 0);
+        assert!(registry.contains(&old.addr));
+    }
+
+    #[test]
+    fn older_session_wins_test() {
+        let registry = SessionRegistry::new([1; 32], LIVENESS);
+        registry.register(handle(0, 2, Direction::Inbound)).unwrap();
+        assert!(registry.register(handle(1, 2, Direction::Inbound)).is_err());
+    }
+
+    #[test]
+    fn dead_session_test() {
+        let registry = SessionRegistry::new([1; 32], LIVENESS);
+
+        // the peer restarted and dialed again
+        registry.register(handle(0, 2, Direction::Inbound)).unwrap();
+        let restarted = SessionHandle {
+            nonce: 1,
+            ..handle(1, 2, Direction::Inbound)
+        };
+        registry.register(restarted).unwrap();
+        assert_eq!(
+            registry
+                .get(&handle(0, 2, Direction::Inbound).addr)
+                .unwrap()
+                .id,
+            1
+        );
+
+        // the connection went silent without being closed
+        let silent = handle(2, 3, Direction::Inbound);
+        *silent.last_received.lock().unwrap() = Instant::now() - LIVENESS;
+        registry.register(silent).unwrap();
+        registry.register(handle(3, 3, Direction::Inbound)).unwrap();
+        assert_eq!(
+            registry
+                .get(&handle(0, 3, Direction::Inbound).addr)
+                .unwrap()
+                .id,
+            3
+        );
+    }
+}
@@ -0,0 +1,194 @@
+use crate::errors::node_errors::RequestError;
+use crate::models::packet_models;
+use std::collections::{HashMap, HashSet, VecDeque};
+use tokio::sync::oneshot;
+use tokio::time::{Duration, Instant};
+
+/// Timed out requests whose late responses are still recognized
+const MAX_EXPIRED: usize = 256;
+
+pub type Responder = oneshot::Sender<Result<packet_models::Response, RequestError>>;
+
+/// Commands other tasks can send to a running session
+pub enum Command {
+    Request {
+        request: packet_models::Request,
+        responder: Responder,
+    },
+}
+
+/// Result of matching an incoming response with the pending requests
+#[derive(Debug, PartialEq, Eq)]
+pub enum Resolution {
+    Delivered,
+    /// the request timed out before, a slow peer isn't at fault
+    Late,
+    /// nothing was waiting for this id, either it was never requested
+    /// or it was already answered
+    Unsolicited,
+}
+
+struct Pending {
+    responder: Responder,
+    deadline: Instant,
+}
+
+/// Requests of one session which are waiting for a response
+pub struct RequestTracker {
+    pending: HashMap<u64, Pending>,
+    /// recently timed out ids, oldest first
+    expired: VecDeque<u64>,
+    expired_ids: HashSet<u64>,
+    timeout: Duration,
+}
+
+impl RequestTracker {
+    pub fn new(timeout: Duration) -> RequestTracker {
+        RequestTracker {
+            pending: HashMap::with_capacity(20),
+            expired: VecDeque::with_capacity(MAX_EXPIRED),
+            expired_ids: HashSet::with_capacity(MAX_EXPIRED),
+            timeout,
+        }
+    }
+
+    /// Picks a random id which isn't used by any pending or recently
+    /// expired request
+    pub fn next_id(&self) -> u64 {
+        loop {
+            let id: u64 = rand::random();
+            if !self.pending.contains_key(&id) && !self.expired_ids.contains(&id) {
+                return id;
+            }
+        }
+    }
+
+    pub fn register(&mut self, id: u64, responder: Responder) {
+        self.pending.insert(
+            id,
+            Pending {
+                responder,
+                deadline: Instant::now() + self.timeout,
+            },
+        );
+    }
+
+    /// Hands the result over to whoever is waiting for it
+    pub fn resolve(
+        &mut self,
+        id: u64,
+        result: Result<packet_models::Response, RequestError>,
+    ) -> Resolution {
+        match self.pending.remove(&id) {
+            Some(pending) => {
+                // the caller may have given up already
+                let _ = pending.responder.send(result);
+                Resolution::Delivered
+            }
+            None if self.expired_ids.remove(&id) => {
+                self.expired.retain(|e| *e != id);
+                Resolution::Late
+            }
+            None => Resolution::Unsolicited,
+        }
+    }
+
+    /// Fails every request whose deadline has passed, returns their amount
+    pub fn expire(&mut self, now: Instant) -> usize {
+        let expired: Vec<u64> = self
+            .pending
+            .iter()
+            .filter(|(_, p)| p.deadline <= now)
+            .map(|(id, _)| *id)
+            .collect();
+
+        for id in expired.iter() {
+            if let Some(pending) = self.pending.remove(id) {
+                let _ = pending.responder.send(Err(RequestError::Timeout));
+            }
+
+            if self.expired.len() == MAX_EXPIRED {
+                if let Some(oldest) = self.expired.pop_front() {
+                    self.expired_ids.remove(&oldest);
+                }
+            }
+            self.expired.push_back(*id);
+            self.expired_ids.insert(*id);
+        }
+
+        expired.len()
+    }
+}
+
+#[cfg(test)]
+mod requests_tests {
+    use super::*;
+
+    fn response(id: u64) -> packet_models::Response {
+        packet_models::Response::get_nodes(packet_models::GetNodesReponse {
+            id,
+            ipv4: None,
+            ipv6: None,
+        })
+    }
+
+    #[test]
+    fn resolve_test() {
+        let mut tracker = RequestTracker::new(Duration::from_secs(10));
+        let (tx, mut rx) = oneshot::channel();
+
+        let id = tracker.next_id();
+        tracker.register(id, tx);
+
+        assert_eq!(tracker.resolve(id, Ok(response(id))), Resolution::Delivered);
+        assert_eq!(rx.try_recv().unwrap().unwrap(), response(id));
+
+        // the second response with the same id is not expected anymore
+        assert_eq!(
+            tracker.resolve(id, Ok(response(id))),
+            Resolution::Unsolicited
+        );
+        assert_eq!(
+            tracker.resolve(id + 1, Ok(response(id + 1))),
+            Resolution::Unsolicited
+        );
+    }
+
+    #[test]
+    fn expire_test() {
+        let mut tracker = RequestTracker::new(Duration::from_secs(10));
+        let (tx, mut rx) = oneshot::channel();
+
+        let id = tracker.next_id();
+        tracker.register(id, tx);
+
+        assert_eq!(tracker.expire(Instant::now()), 0);
+        assert_eq!(tracker.expire(Instant::now() + Duration::from_secs(11)), 1);
+        assert!(matches!(rx.try_recv().unwrap(), Err(RequestError::Timeout)));
+
+        // a late response is recognized once
+        assert_eq!(tracker.resolve(id, Ok(response(id))), Resolution::Late);
+        assert_eq!(
+            tracker.resolve(id, Ok(response(id))),
+            Resolution::Unsolicited
+        );
+    }
+
+    #[test]
+    fn expired_capacity_test() {
+        let mut tracker = RequestTracker::new(Duration::from_secs(10));
+        let ids: Vec<u64> = (0..MAX_EXPIRED as u64 + 1).collect();
+        for id in &ids {
+            tracker.register(*id, oneshot::channel().0);
+        }
+        tracker.expire(Instant::now() + Duration::from_secs(11));
+
+        // only the most recent expired ids are remembered
+        assert_eq!(tracker.expired.len(), MAX_EXPIRED);
+        let remembered = ids
+            .iter()
+            .filter(|id| tracker.resolve(**id, Ok(response(**id))) == Resolution::Late)
+            .count();
+        assert_eq!(remembered, MAX_EXPIRED);
+    }
+}
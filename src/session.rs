@@ -0,0 +1,489 @@
+use crate::errors::*;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::ChaCha20Poly1305;
+use hkdf::Hkdf;
+use rand_core::OsRng;
+use sha2::Digest;
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, ReusableSecret, StaticSecret};
+
+const PROTOCOL_NAME: &[u8] = b"aplo_XX_25519_ChaChaPoly_SHA256";
+
+/// Size of the frame header: u32 length of the sealed body and u64 counter
+pub const FRAME_HEADER_SIZE: usize = 12;
+
+/// Size of the Poly1305 tag appended to every frame body
+pub const TAG_SIZE: usize = 16;
+
+/// Side of the key exchange, the node which dialed is the initiator
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Role {
+    Initiator,
+    Responder,
+}
+
+/// Splits frame header into the body length and the frame counter
+pub fn parse_header(header: &[u8; FRAME_HEADER_SIZE]) -> (usize, u64) {
+    let size = u32::from_be_bytes(header[..4].try_into().unwrap()) as usize;
+    let counter = u64::from_be_bytes(header[4..].try_into().unwrap());
+    (size, counter)
+}
+
+/// Key and frame counter for one direction of the session
+pub struct CipherState {
+    cipher: ChaCha20Poly1305,
+    counter: u64,
+}
+
+impl CipherState {
+    fn new(key: [u8; 32]) -> CipherState {
+        CipherState {
+            cipher: ChaCha20Poly1305::new(&key.into()),
+            counter: 0,
+        }
+    }
+
+    fn nonce(counter: u64) -> [u8; 12] {
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(&counter.to_be_bytes());
+        nonce
+    }
+
+    /// Seals `payload` into a complete frame: header followed by the
+    /// ciphertext and the tag, the header is authenticated as well
+    pub fn seal(&mut self, payload: &[u8]) -> Result<Vec<u8>, node_errors::NodeError> {
+        let counter = self.counter;
+        self.counter = counter
+            .checked_add(1)
+            .ok_or_else(|| node_errors::NodeError::new("Frame counter exhausted".to_string()))?;
+
+        let size = u32::try_from(payload.len() + TAG_SIZE)
+            .map_err(|_| node_errors::NodeError::new("Frame is too big".to_string()))?;
+
+        let mut frame: Vec<u8> = Vec::with_capacity(FRAME_HEADER_SIZE + size as usize);
+        frame.extend_from_slice(&size.to_be_bytes());
+        frame.extend_from_slice(&counter.to_be_bytes());
+
+        let sealed = self
+            .cipher
+            .encrypt(
+                &Self::nonce(counter).into(),
+                Payload {
+                    msg: payload,
+                    aad: &frame,
+                },
+            )
+            .map_err(|_| node_errors::NodeError::new("Failed to seal frame".to_string()))?;
+        frame.extend_from_slice(&sealed);
+
+        Ok(frame)
+    }
+
+    /// Opens the body of a frame, frames have to arrive strictly in order
+    /// and a frame with a bad tag is never decoded
+    pub fn open(&mut self, header: &[u8; FRAME_HEADER_SIZE], body: &[u8]) -> ResultSmall<Vec<u8>> {
+        let (_, counter) = parse_header(header);
+        if counter != self.counter {
+            return Err(node_errors::UnexpectedCounter {
+                expected: self.counter,
+                got: counter,
+            }
+            .into());
+        }
+
+        let payload = self
+            .cipher
+            .decrypt(
+                &Self::nonce(counter).into(),
+                Payload {
+                    msg: body,
+                    aad: header,
+                },
+            )
+            .map_err(|_| node_errors::AuthenticationFailed {})?;
+
+        // the sender never uses u64::MAX, so this can't overflow
+        self.counter = counter + 1;
+
+        Ok(payload)
+    }
+}
+
+pub struct Session {
+    pub send: CipherState,
+    pub recv: CipherState,
+}
+
+impl Session {
+    fn new(role: Role, initiator_key: [u8; 32], responder_key: [u8; 32]) -> Session {
+        let (send, recv) = match role {
+            Role::Initiator => (initiator_key, responder_key),
+            Role::Responder => (responder_key, initiator_key),
+        };
+
+        Session {
+            send: CipherState::new(send),
+            recv: CipherState::new(recv),
+        }
+    }
+}
+
+/// Size of the first handshake message: initiator's ephemeral key
+pub const HANDSHAKE_MSG1_SIZE: usize = 32;
+
+/// Size of the second handshake message: responder's ephemeral key,
+/// encrypted static key and an empty encrypted payload
+pub const HANDSHAKE_MSG2_SIZE: usize = 32 + 32 + TAG_SIZE + TAG_SIZE;
+
+/// Size of the third handshake message: initiator's encrypted static key
+/// and an empty encrypted payload
+pub const HANDSHAKE_MSG3_SIZE: usize = 32 + TAG_SIZE + TAG_SIZE;
+
+/// Noise XX style handshake, both sides prove that they own their static
+/// keys and end up with a transport session bound to the whole transcript
+///
+/// ```text
+/// -> e
+/// <- e, ee, s, es
+/// -> s, se
+/// ```
+pub struct Handshake {
+    role: Role,
+    s: StaticSecret,
+    e: ReusableSecret,
+    rs: Option<PublicKey>,
+    re: Option<PublicKey>,
+    ck: [u8; 32],
+    h: [u8; 32],
+    k: Option<ChaCha20Poly1305>,
+    n: u64,
+}
+
+impl Handshake {
+    pub fn new(role: Role, s: StaticSecret) -> Handshake {
+        let h: [u8; 32] = Sha256::digest(PROTOCOL_NAME).into();
+
+        Handshake {
+            role,
+            s,
+            e: ReusableSecret::random_from_rng(OsRng),
+            rs: None,
+            re: None,
+            ck: h,
+            h,
+            k: None,
+            n: 0,
+        }
+    }
+
+    fn mix_hash(&mut self, data: &[u8]) {
+        let mut hasher = Sha256::new();
+        hasher.update(self.h);
+        hasher.update(data);
+        self.h = hasher.finalize().into();
+    }
+
+    fn mix_key(&mut self, input: &[u8]) {
+        let (ck, k) = hkdf2(&self.ck, input);
+        self.ck = ck;
+        self.k = Some(ChaCha20Poly1305::new(&k.into()));
+        self.n = 0;
+    }
+
+    fn encrypt_and_hash(&mut self, plaintext: &[u8]) -> Result<Vec<u8>, node_errors::NodeError> {
+        let cipher = self
+            .k
+            .as_ref()
+            .ok_or_else(|| node_errors::NodeError::new("Handshake has no key".to_string()))?;
+        let ciphertext = cipher
+            .encrypt(
+                &CipherState::nonce(self.n).into(),
+                Payload {
+                    msg: plaintext,
+                    aad: &self.h,
+                },
+            )
+            .map_err(|_| node_errors::NodeError::new("Failed to seal handshake".to_string()))?;
+        self.n += 1;
+        self.mix_hash(&ciphertext);
+
+        Ok(ciphertext)
+    }
+
+    fn decrypt_and_hash(
+        &mut self,
+        ciphertext: &[u8],
+    ) -> Result<Vec<u8>, node_errors::AuthenticationFailed> {
+        let cipher = self
+            .k
+            .as_ref()
+            .ok_or(node_errors::AuthenticationFailed {})?;
+        let plaintext = cipher
+            .decrypt(
+                &CipherState::nonce(self.n).into(),
+                Payload {
+                    msg: ciphertext,
+                    aad: &self.h,
+                },
+            )
+            .map_err(|_| node_errors::AuthenticationFailed {})?;
+        self.n += 1;
+        self.mix_hash(ciphertext);
+
+        Ok(plaintext)
+    }
+
+    /// -> e
+    pub fn write_message_1(&mut self) -> [u8; HANDSHAKE_MSG1_SIZE] {
+        let e = PublicKey::from(&self.e);
+        self.mix_hash(e.as_bytes());
+        e.to_bytes()
+    }
+
+    /// -> e
+    pub fn read_message_1(&mut self, msg: &[u8; HANDSHAKE_MSG1_SIZE]) {
+        let re = PublicKey::from(*msg);
+        self.mix_hash(re.as_bytes());
+        self.re = Some(re);
+    }
+
+    /// <- e, ee, s, es
+    pub fn write_message_2(&mut self) -> ResultSmall<Vec<u8>> {
+        let re = self.re.ok_or(node_errors::HandshakeFailed {})?;
+        let mut msg: Vec<u8> = Vec::with_capacity(HANDSHAKE_MSG2_SIZE);
+
+        let e = PublicKey::from(&self.e);
+        self.mix_hash(e.as_bytes());
+        msg.extend_from_slice(e.as_bytes());
+
+        self.mix_key(self.e.diffie_hellman(&re).as_bytes());
+
+        let s = PublicKey::from(&self.s);
+        msg.extend_from_slice(&self.encrypt_and_hash(s.as_bytes())?);
+
+        self.mix_key(self.s.diffie_hellman(&re).as_bytes());
+
+        msg.extend_from_slice(&self.encrypt_and_hash(&[])?);
+
+        Ok(msg)
+    }
+
+    /// <- e, ee, s, es
+    pub fn read_message_2(&mut self, msg: &[u8; HANDSHAKE_MSG2_SIZE]) -> ResultSmall<()> {
+        let re = PublicKey::from(<[u8; 32]>::try_from(&msg[..32])?);
+        self.mix_hash(re.as_bytes());
+        self.re = Some(re);
+
+        self.mix_key(self.e.diffie_hellman(&re).as_bytes());
+
+        let rs = self.decrypt_and_hash(&msg[32..32 + 32 + TAG_SIZE])?;
+        let rs = PublicKey::from(<[u8; 32]>::try_from(rs.as_slice())?);
+        self.rs = Some(rs);
+
+        self.mix_key(self.e.diffie_hellman(&rs).as_bytes());
+
+        self.decrypt_and_hash(&msg[32 + 32 + TAG_SIZE..])?;
+
+        Ok(())
+    }
+
+    /// -> s, se
+    pub fn write_message_3(&mut self) -> ResultSmall<Vec<u8>> {
+        let re = self.re.ok_or(node_errors::HandshakeFailed {})?;
+        let mut msg: Vec<u8> = Vec::with_capacity(HANDSHAKE_MSG3_SIZE);
+
+        let s = PublicKey::from(&self.s);
+        msg.extend_from_slice(&self.encrypt_and_hash(s.as_bytes())?);
+
+        self.mix_key(self.s.diffie_hellman(&re).as_bytes());
+
+        msg.extend_from_slice(&self.encrypt_and_hash(&[])?);
+
+        Ok(msg)
+    }
+
+    /// -> s, se
+    pub fn read_message_3(&mut self, msg: &[u8; HANDSHAKE_MSG3_SIZE]) -> ResultSmall<()> {
+        let rs = self.decrypt_and_hash(&msg[..32 + TAG_SIZE])?;
+        let rs = PublicKey::from(<[u8; 32]>::try_from(rs.as_slice())?);
+        self.rs = Some(rs);
+
+        self.mix_key(self.e.diffie_hellman(&rs).as_bytes());
+
+        self.decrypt_and_hash(&msg[32 + TAG_SIZE..])?;
+
+        Ok(())
+    }
+
+    /// Splits the handshake into the transport session,
+    /// returns it together with the authenticated static key of the peer
+    pub fn finish(self) -> Result<(Session, PublicKey), node_errors::HandshakeFailed> {
+        let rs = self.rs.ok_or(node_errors::HandshakeFailed {})?;
+        let (initiator_key, responder_key) = hkdf2(&self.ck, &[]);
+
+        Ok((Session::new(self.role, initiator_key, responder_key), rs))
+    }
+}
+
+fn hkdf2(ck: &[u8; 32], input: &[u8]) -> ([u8; 32], [u8; 32]) {
+    let hk = Hkdf::<Sha256>::new(Some(ck), input);
+    let mut okm = [0u8; 64];
+    hk.expand(&[], &mut okm).unwrap();
+
+    (okm[..32].try_into().unwrap(), okm[32..].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod session_tests {
+    use super::*;
+
+    fn handshake(
+        a_static: StaticSecret,
+        b_static: StaticSecret,
+    ) -> ResultSmall<((Session, PublicKey), (Session, PublicKey))> {
+        let mut a = Handshake::new(Role::Initiator, a_static);
+        let mut b = Handshake::new(Role::Responder, b_static);
+
+        b.read_message_1(&a.write_message_1());
+        a.read_message_2(&b.write_message_2()?.try_into().unwrap())?;
+        b.read_message_3(&a.write_message_3()?.try_into().unwrap())?;
+
+        Ok((a.finish()?, b.finish()?))
+    }
+
+    fn pair() -> (Session, Session) {
+        let ((a, _), (b, _)) = handshake(
+            StaticSecret::random_from_rng(OsRng),
+            StaticSecret::random_from_rng(OsRng),
+        )
+        .unwrap();
+        (a, b)
+    }
+
+    fn open(state: &mut CipherState, frame: &[u8]) -> ResultSmall<Vec<u8>> {
+        let header: [u8; FRAME_HEADER_SIZE] = frame[..FRAME_HEADER_SIZE].try_into().unwrap();
+        let (size, _) = parse_header(&header);
+        assert_eq!(size, frame.len() - FRAME_HEADER_SIZE);
+        state.open(&header, &frame[FRAME_HEADER_SIZE..])
+    }
+
+    #[test]
+    fn roundtrip_test() {
+        let (mut a, mut b) = pair();
+
+        for msg in [&b"first"[..], &b"second"[..]] {
+            let frame = a.send.seal(msg).unwrap();
+            assert_eq!(open(&mut b.recv, &frame).unwrap(), msg);
+
+            let frame = b.send.seal(msg).unwrap();
+            assert_eq!(open(&mut a.recv, &frame).unwrap(), msg);
+        }
+    }
+
+    #[test]
+    fn keystream_not_reused_test() {
+        let (mut a, _) = pair();
+
+        let first = a.send.seal(&[0u8; 32]).unwrap();
+        let second = a.send.seal(&[0u8; 32]).unwrap();
+
+        assert_ne!(first[FRAME_HEADER_SIZE..], second[FRAME_HEADER_SIZE..]);
+    }
+
+    #[test]
+    fn directions_differ_test() {
+        let (mut a, mut b) = pair();
+
+        let from_a = a.send.seal(&[0u8; 32]).unwrap();
+        let from_b = b.send.seal(&[0u8; 32]).unwrap();
+
+        assert_ne!(from_a, from_b);
+    }
+
+    #[test]
+    fn replay_rejected_test() {
+        let (mut a, mut b) = pair();
+
+        let frame = a.send.seal(b"data").unwrap();
+        open(&mut b.recv, &frame).unwrap();
+
+        let err = open(&mut b.recv, &frame).unwrap_err();
+        assert!(err.is::<node_errors::UnexpectedCounter>());
+    }
+
+    #[test]
+    fn out_of_order_rejected_test() {
+        let (mut a, mut b) = pair();
+
+        a.send.seal(b"first").unwrap();
+        let second = a.send.seal(b"second").unwrap();
+
+        let err = open(&mut b.recv, &second).unwrap_err();
+        assert!(err.is::<node_errors::UnexpectedCounter>());
+    }
+
+    #[test]
+    fn tampered_body_rejected_test() {
+        let (mut a, mut b) = pair();
+
+        let mut frame = a.send.seal(b"data").unwrap();
+        frame[FRAME_HEADER_SIZE] ^= 1;
+
+        let err = open(&mut b.recv, &frame).unwrap_err();
+        assert!(err.is::<node_errors::AuthenticationFailed>());
+    }
+
+    #[test]
+    fn tampered_length_rejected_test() {
+        let (mut a, mut b) = pair();
+
+        let mut frame = a.send.seal(b"data").unwrap();
+        frame.push(0);
+        let size = (frame.len() - FRAME_HEADER_SIZE) as u32;
+        frame[..4].copy_from_slice(&size.to_be_bytes());
+
+        let err = open(&mut b.recv, &frame).unwrap_err();
+        assert!(err.is::<node_errors::AuthenticationFailed>());
+    }
+
+    #[test]
+    fn handshake_identities_test() {
+        let a_static = StaticSecret::random_from_rng(OsRng);
+        let b_static = StaticSecret::random_from_rng(OsRng);
+        let a_public = PublicKey::from(&a_static);
+        let b_public = PublicKey::from(&b_static);
+
+        let ((_, a_sees), (_, b_sees)) = handshake(a_static, b_static).unwrap();
+
+        assert_eq!(a_sees, b_public);
+        assert_eq!(b_sees, a_public);
+    }
+
+    #[test]
+    fn handshake_tampered_static_test() {
+        let mut a = Handshake::new(Role::Initiator, StaticSecret::random_from_rng(OsRng));
+        let mut b = Handshake::new(Role::Responder, StaticSecret::random_from_rng(OsRng));
+
+        b.read_message_1(&a.write_message_1());
+        let mut msg2 = b.write_message_2().unwrap();
+        msg2[40] ^= 1;
+
+        let err = a.read_message_2(&msg2.try_into().unwrap()).unwrap_err();
+        assert!(err.is::<node_errors::AuthenticationFailed>());
+    }
+
+    #[test]
+    fn handshake_substituted_ephemeral_test() {
+        // a man in the middle replacing the ephemeral key can't complete
+        // the handshake without the responder's static key
+        let mut a = Handshake::new(Role::Initiator, StaticSecret::random_from_rng(OsRng));
+        let mut b = Handshake::new(Role::Responder, StaticSecret::random_from_rng(OsRng));
+        let mut m = Handshake::new(Role::Initiator, StaticSecret::random_from_rng(OsRng));
+
+        a.write_message_1();
+        b.read_message_1(&m.write_message_1());
+        let msg2 = b.write_message_2().unwrap();
+
+        assert!(a.read_message_2(&msg2.try_into().unwrap()).is_err());
+    }
+}
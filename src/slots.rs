@@ -0,0 +1,248 @@
+use crate::models::peers_dump::Direction;
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+/// Connection limits of the node
+#[derive(Clone, Debug)]
+pub struct Limits {
+    pub max_inbound: usize,
+    pub max_outbound: usize,
+    pub max_per_ip: usize,
+    pub max_per_subnet: usize,
+    /// inbound slots on top of `max_inbound` only whitelisted peers can take
+    pub reserved: usize,
+    /// peers which are exempt from the per-IP and per-subnet limits
+    pub whitelist: HashSet<IpAddr>,
+}
+
+struct Slot {
+    ip: IpAddr,
+    direction: Direction,
+    whitelisted: bool,
+    connected_at: Instant,
+    /// packets which brought something new to the node
+    useful: u64,
+    evict: Arc<Notify>,
+}
+
+struct Table {
+    limits: Limits,
+    slots: HashMap<u64, Slot>,
+    next_id: u64,
+}
+
+/// /24 for IPv4 and /64 for IPv6 addresses
+fn subnet(ip: &IpAddr) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => {
+            let o = ip.octets();
+            IpAddr::from([o[0], o[1], o[2], 0])
+        }
+        IpAddr::V6(ip) => {
+            let mut o = ip.octets();
+            o[8..].fill(0);
+            IpAddr::from(o)
+        }
+    }
+}
+
+impl Table {
+    fn count(&self, direction: Direction) -> usize {
+        self.slots
+            .values()
+            .filter(|s| s.direction == direction)
+            .count()
+    }
+
+    fn within_address_limits(&self, ip: &IpAddr) -> bool {
+        let same_ip = self.slots.values().filter(|s| s.ip == *ip).count();
+        let same_subnet = self
+            .slots
+            .values()
+            .filter(|s| subnet(&s.ip) == subnet(ip))
+            .count();
+
+        same_ip < self.limits.max_per_ip && same_subnet < self.limits.max_per_subnet
+    }
+
+    /// Least useful inbound peer, the newest one among equally useful
+    fn eviction_candidate(&self) -> Option<u64> {
+        self.slots
+            .iter()
+            .filter(|(_, s)| s.direction == Direction::Inbound && !s.whitelisted)
+            .min_by_key(|(_, s)| (s.useful, std::cmp::Reverse(s.connected_at)))
+            .map(|(id, _)| *id)
+    }
+}
+
+/// Connection slots shared by the accept loop and the dialer
+#[derive(Clone)]
+pub struct Slots {
+    table: Arc<Mutex<Table>>,
+}
+
+/// Slot taken by a connection, it is freed on drop
+pub struct SlotGuard {
+    table: Arc<Mutex<Table>>,
+    id: u64,
+    evict: Arc<Notify>,
+}
+
+impl SlotGuard {
+    /// Completes once the slot was given to a more useful peer
+    pub async fn evicted(&self) {
+        self.evict.notified().await
+    }
+
+    /// Marks the peer as useful, so it is evicted last
+    pub fn record_useful(&self) {
+        if let Some(slot) = self.table.lock().unwrap().slots.get_mut(&self.id) {
+            slot.useful += 1;
+        }
+    }
+}
+
+impl Drop for SlotGuard {
+    fn drop(&mut self) {
+        self.table.lock().unwrap().slots.remove(&self.id);
+    }
+}
+
+impl Slots {
+    pub fn new(limits: Limits) -> Slots {
+        Slots {
+            table: Arc::new(Mutex::new(Table {
+                limits,
+                slots: HashMap::new(),
+                next_id: 0,
+            })),
+        }
+    }
+
+    /// Takes a slot for a connection with `ip`, when all inbound slots are
+    /// taken the least useful inbound peer is evicted to make room
+    pub fn acquire(&self, ip: IpAddr, direction: Direction) -> Option<SlotGuard> {
+        let mut table = self.table.lock().unwrap();
+        let whitelisted = table.limits.whitelist.contains(&ip);
+
+        if !whitelisted && !table.within_address_limits(&ip) {
+            return None;
+        }
+
+        match direction {
+            Direction::Outbound => {
+                if table.count(Direction::Outbound) >= table.limits.max_outbound {
+                    return None;
+                }
+            }
+            Direction::Inbound => {
+                let inbound = table.count(Direction::Inbound);
+                let reserved = if whitelisted {
+                    table.limits.reserved
+                } else {
+                    0
+                };
+
+                if inbound >= table.limits.max_inbound + reserved {
+                    let victim = table.eviction_candidate()?;
+                    if let Some(slot) = table.slots.remove(&victim) {
+                        slot.evict.notify_one();
+                    }
+                }
+            }
+        }
+
+        let id = table.next_id;
+        table.next_id += 1;
+        let evict = Arc::new(Notify::new());
+        table.slots.insert(
+            id,
+            Slot {
+                ip,
+                direction,
+                whitelisted,
+                connected_at: Instant::now(),
+                useful: 0,
+                evict: evict.clone(),
+            },
+        );
+
+        Some(SlotGuard {
+            table: self.table.clone(),
+            id,
+            evict,
+        })
+    }
+}
+
+#[cfg(test)]
+mod slots_tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn limits() -> Limits {
+        Limits {
+            max_inbound: 2,
+            max_outbound: 1,
+            max_per_ip: 1,
+            max_per_subnet: 2,
+            reserved: 1,
+            whitelist: HashSet::from(["10.0.0.9".parse().unwrap()]),
+        }
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn address_limits_test() {
+        let slots = Slots::new(limits());
+
+        let _a = slots.acquire(ip("10.0.0.1"), Direction::Inbound).unwrap();
+        assert!(slots.acquire(ip("10.0.0.1"), Direction::Inbound).is_none());
+
+        let _b = slots.acquire(ip("10.0.0.2"), Direction::Outbound).unwrap();
+        assert!(slots.acquire(ip("10.0.0.3"), Direction::Inbound).is_none());
+
+        // whitelisted peers ignore the subnet limit
+        assert!(slots.acquire(ip("10.0.0.9"), Direction::Inbound).is_some());
+    }
+
+    #[test]
+    fn release_test() {
+        let slots = Slots::new(limits());
+
+        let a = slots.acquire(ip("10.0.0.1"), Direction::Outbound).unwrap();
+        assert!(slots.acquire(ip("10.0.2.1"), Direction::Outbound).is_none());
+
+        drop(a);
+        assert!(slots.acquire(ip("10.0.2.1"), Direction::Outbound).is_some());
+    }
+
+    #[tokio::test]
+    async fn eviction_test() {
+        let slots = Slots::new(limits());
+
+        let useful = slots.acquire(ip("10.0.2.1"), Direction::Inbound).unwrap();
+        useful.record_useful();
+        let idle = slots.acquire(ip("10.0.3.1"), Direction::Inbound).unwrap();
+
+        // full, the idle peer makes room for the new one
+        let _new = slots.acquire(ip("10.0.4.1"), Direction::Inbound).unwrap();
+        tokio::time::timeout(Duration::from_secs(1), idle.evicted())
+            .await
+            .unwrap();
+
+        // the whitelisted peer takes the reserved slot without evicting
+        let _w = slots.acquire(ip("10.0.0.9"), Direction::Inbound).unwrap();
+        assert!(
+            tokio::time::timeout(Duration::from_millis(10), useful.evicted())
+                .await
+                .is_err()
+        );
+    }
+}
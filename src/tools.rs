@@ -23,3 +23,21 @@ pub fn current_time() -> u64 {
         .unwrap()
         .as_secs()
 }
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    let mut to_return = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        to_return.push_str(&format!("{:02x}", byte));
+    }
+    to_return
+}
+
+pub fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}